@@ -0,0 +1,518 @@
+use crate::gamekey::gesture::Gesture;
+use crate::macro_runner::{Macro, MacroRunner};
+use crate::touch_emulator::{animate, MotionPath, TouchEmulator};
+use crate::GameKeyData;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::Formatter;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// Emulator slots reserved for every trigger, macros may touch with that many fingers.
+pub const FINGERS_PER_SLOT: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    Tap { x: i32, y: i32 },
+    Swipe { path: MotionPath, hold: bool },
+    Macro(Macro),
+}
+
+/// Repeated tapping while the trigger is held.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TurboMode {
+    pub taps_per_second: u32,
+    /// Share of each tap period the touch is held down, in percent.
+    pub duty_cycle: u32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidTapRate,
+    InvalidDutyCycle,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidTapRate => write!(f, "Invalid tap rate! Min rate is 1, max 30."),
+            Error::InvalidDutyCycle => write!(f, "Invalid duty cycle! Min is 1%, max 99%."),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl TurboMode {
+    pub fn new(taps_per_second: u32, duty_cycle: u32) -> Result<Self, Error> {
+        if taps_per_second == 0 || taps_per_second > 30 {
+            return Err(Error::InvalidTapRate);
+        }
+
+        if duty_cycle == 0 || duty_cycle > 99 {
+            return Err(Error::InvalidDutyCycle);
+        }
+
+        Ok(Self {
+            taps_per_second,
+            duty_cycle,
+        })
+    }
+
+    /// Returns how long the touch is held down and lifted within one tap.
+    pub fn phases(&self) -> (Duration, Duration) {
+        let period = Duration::from_secs(1) / self.taps_per_second;
+        let down = period * self.duty_cycle / 100;
+
+        (down, period - down)
+    }
+}
+
+/// What the trigger of a running task did, tasks only stop in between frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Held,
+    /// Turbo and held swipes end, macros follow their [`ReleasePolicy`].
+    ///
+    /// [`ReleasePolicy`]: crate::macro_runner::ReleasePolicy
+    Released,
+    /// Everything ends and lifts its fingers.
+    Cancelled,
+}
+
+enum Running {
+    Touch,
+    Task {
+        signal: watch::Sender<Signal>,
+        handle: JoinHandle<()>,
+    },
+}
+
+/// Drives the bound [`Action`] of every trigger slot on the [`TouchEmulator`].
+pub struct ActionRunner {
+    emulator: Arc<Mutex<TouchEmulator>>,
+    running: Vec<Option<Running>>,
+}
+
+async fn wait_released(signal: &mut watch::Receiver<Signal>) {
+    while *signal.borrow_and_update() == Signal::Held {
+        if signal.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Waits until the task is cancelled, forever once nobody can cancel it anymore.
+pub async fn wait_cancelled(signal: &mut watch::Receiver<Signal>) {
+    while *signal.borrow_and_update() != Signal::Cancelled {
+        if signal.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+async fn swipe_task(
+    emulator: Arc<Mutex<TouchEmulator>>,
+    slot: usize,
+    path: MotionPath,
+    hold: bool,
+    mut signal: watch::Receiver<Signal>,
+) {
+    let res = animate(&emulator, slot, &path, &mut signal).await;
+
+    if let Err(e) = &res {
+        log::warn!("Failed to swipe in slot {}: {}", slot, e);
+    } else if hold {
+        wait_released(&mut signal).await;
+    }
+
+    if let Err(e) = emulator.lock().await.stop_tap(slot).await {
+        log::warn!("Failed to finish swipe in slot {}: {}", slot, e);
+    }
+}
+
+async fn turbo_task(
+    emulator: Arc<Mutex<TouchEmulator>>,
+    slot: usize,
+    (x, y): (i32, i32),
+    mode: TurboMode,
+    mut signal: watch::Receiver<Signal>,
+) {
+    let (down, up) = mode.phases();
+
+    loop {
+        if let Err(e) = emulator.lock().await.start_tap(slot, x, y).await {
+            log::warn!("Failed to turbo tap in slot {}: {}", slot, e);
+            break;
+        }
+
+        tokio::select! {
+            _ = sleep(down) => {}
+            _ = wait_released(&mut signal) => break,
+        }
+
+        if let Err(e) = emulator.lock().await.stop_tap(slot).await {
+            log::warn!("Failed to turbo tap in slot {}: {}", slot, e);
+            break;
+        }
+
+        tokio::select! {
+            _ = sleep(up) => {}
+            _ = wait_released(&mut signal) => break,
+        }
+    }
+
+    // released in the middle of a tap, or a frame failed - never leave it down
+    if let Err(e) = emulator.lock().await.stop_tap(slot).await {
+        log::warn!("Failed to finish turbo in slot {}: {}", slot, e);
+    }
+}
+
+async fn macro_task(
+    emulator: Arc<Mutex<TouchEmulator>>,
+    slot: usize,
+    macro_: Macro,
+    signal: watch::Receiver<Signal>,
+) {
+    let runner = MacroRunner::new(&emulator, slot * FINGERS_PER_SLOT, FINGERS_PER_SLOT);
+
+    match runner.run(&macro_, signal).await {
+        Ok(true) => {}
+        Ok(false) => log::debug!("Macro in slot {} was cancelled", slot),
+        Err(e) => log::warn!("Failed to play macro in slot {}: {}", slot, e),
+    }
+}
+
+impl ActionRunner {
+    /// `emulator` needs [`FINGERS_PER_SLOT`] slots for each of the `slot_count` slots.
+    pub fn new(emulator: Arc<Mutex<TouchEmulator>>, slot_count: usize) -> Self {
+        let mut running = Vec::new();
+        running.resize_with(slot_count, || None);
+
+        Self { emulator, running }
+    }
+
+    fn is_busy(&self, slot: usize) -> bool {
+        match self.running.get(slot) {
+            Some(Some(Running::Touch)) => true,
+            Some(Some(Running::Task { handle, .. })) => !handle.is_finished(),
+            _ => false,
+        }
+    }
+
+    fn spawn<F>(&mut self, slot: usize, task: impl FnOnce(watch::Receiver<Signal>) -> F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (signal, signal_rx) = watch::channel(Signal::Held);
+        let handle = tokio::spawn(task(signal_rx));
+
+        self.running[slot] = Some(Running::Task { signal, handle });
+    }
+
+    pub async fn press(
+        &mut self,
+        slot: usize,
+        data: &GameKeyData,
+        gesture: Gesture,
+    ) -> anyhow::Result<()> {
+        if self.is_busy(slot) {
+            log::debug!("Slot {} is still busy, ignoring press", slot);
+            return Ok(());
+        }
+
+        let Some(action) = data.action_for(gesture) else {
+            return Ok(());
+        };
+
+        let emulator = self.emulator.clone();
+        let finger = slot * FINGERS_PER_SLOT;
+
+        match (action, data.turbo) {
+            (Action::Tap { x, y }, Some(mode)) => {
+                let point = (*x, *y);
+                self.spawn(slot, |signal| {
+                    turbo_task(emulator, finger, point, mode, signal)
+                });
+            }
+            (Action::Tap { x, y }, None) => {
+                emulator.lock().await.start_tap(finger, *x, *y).await?;
+                self.running[slot] = Some(Running::Touch);
+            }
+            (Action::Swipe { path, hold }, _) => {
+                let (path, hold) = (path.clone(), *hold);
+                self.spawn(slot, |signal| {
+                    swipe_task(emulator, finger, path, hold, signal)
+                });
+            }
+            (Action::Macro(macro_), _) => {
+                let macro_ = macro_.clone();
+                self.spawn(slot, |signal| macro_task(emulator, slot, macro_, signal));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops whatever runs in `slot` at its next frame, unlike [`Self::release`] which lets
+    /// macros finish, and lifts its fingers.
+    pub async fn cancel(&mut self, slot: usize) -> anyhow::Result<()> {
+        if let Some(Running::Task { signal, handle }) =
+            self.running.get_mut(slot).and_then(|r| r.take())
+        {
+            // the task may already be done, nobody listens then
+            let _ = signal.send(Signal::Cancelled);
+
+            if let Err(e) = handle.await {
+                log::warn!("Task in slot {} failed: {}", slot, e);
+            }
+        }
+
+        let mut emulator = self.emulator.lock().await;
+        for finger in slot * FINGERS_PER_SLOT..(slot + 1) * FINGERS_PER_SLOT {
+            emulator.stop_tap(finger).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn release(&mut self, slot: usize) -> anyhow::Result<()> {
+        match self.running.get_mut(slot).and_then(|r| r.take()) {
+            Some(Running::Touch) => {
+                let finger = slot * FINGERS_PER_SLOT;
+                self.emulator.lock().await.stop_tap(finger).await?
+            }
+            Some(Running::Task { signal, handle }) => {
+                // the task may already be done, nobody listens then
+                let _ = signal.send(Signal::Released);
+                self.running[slot] = Some(Running::Task { signal, handle });
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macro_runner::{MacroStep, ReleasePolicy};
+    use crate::touch_emulator::Curve;
+    use crate::utils::clock::Clock;
+    use evdev_rs::enums::{EventCode, EV_ABS, EV_KEY, EV_SYN};
+    use evdev_rs::InputEvent;
+    use tokio::sync::mpsc::Receiver;
+    use tokio::time::Instant;
+
+    const SLOT: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT);
+    const TRACKING_ID: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID);
+    const TOUCH: EventCode = EventCode::EV_KEY(EV_KEY::BTN_TOUCH);
+    const FINGER: EventCode = EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER);
+    const SYN: EventCode = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+
+    /// Longest a cancelled task may take to stop, a bit over one motion frame.
+    const NEXT_FRAME: Duration = Duration::from_millis(10);
+
+    const LIFT: [(EventCode, i32); 5] = [
+        (SLOT, 0),
+        (TRACKING_ID, -1),
+        (TOUCH, 0),
+        (FINGER, 0),
+        (SYN, 0),
+    ];
+
+    async fn collect(mut rx: Receiver<InputEvent>) -> Vec<(EventCode, i32)> {
+        let mut events = Vec::new();
+
+        while let Some(ev) = rx.recv().await {
+            events.push((ev.event_code, ev.value));
+        }
+
+        events
+    }
+
+    /// Presses slot 0 bound to `data` for `held`, then releases or cancels it.
+    ///
+    /// Returns the emulator's events and how long the slot took to stop.
+    async fn hold(
+        data: &GameKeyData,
+        held: Duration,
+        cancel: bool,
+    ) -> (Vec<(EventCode, i32)>, Duration) {
+        let (emulator, rx) = TouchEmulator::new(FINGERS_PER_SLOT as u8, Clock::default()).unwrap();
+        let emulator = Arc::new(Mutex::new(emulator));
+        let events = tokio::spawn(collect(rx));
+        let mut runner = ActionRunner::new(emulator.clone(), 1);
+
+        runner.press(0, data, Gesture::Press).await.unwrap();
+        sleep(held).await;
+
+        let stopping = Instant::now();
+        if cancel {
+            runner.cancel(0).await.unwrap();
+        } else {
+            runner.release(0).await.unwrap();
+            // let the task see the release
+            sleep(Duration::from_millis(1)).await;
+        }
+        let stopped_after = stopping.elapsed();
+
+        assert!(!runner.is_busy(0));
+        assert!(emulator.lock().await.active_slots().is_empty());
+
+        drop(runner);
+        drop(emulator);
+
+        (events.await.unwrap(), stopped_after)
+    }
+
+    fn turbo_data() -> GameKeyData {
+        GameKeyData {
+            action: Some(Action::Tap { x: 100, y: 100 }),
+            // 50ms down, 50ms up
+            turbo: Some(TurboMode::new(10, 50).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Holds a turbo tap for `held`, returns the emulator's events.
+    async fn turbo(held: Duration) -> Vec<(EventCode, i32)> {
+        hold(&turbo_data(), held, false).await.0
+    }
+
+    /// Checks that no frame was cut off, every one selects its slot first and ends with
+    /// `SYN_REPORT`.
+    fn assert_whole_frames(events: &[(EventCode, i32)]) {
+        assert_eq!(events.last().map(|ev| ev.0), Some(SYN));
+
+        for frame in events.split_inclusive(|ev| ev.0 == SYN) {
+            assert_eq!(frame[0].0, SLOT, "{:?}", frame);
+            assert_eq!(count_code(frame, SLOT), 1, "{:?}", frame);
+        }
+    }
+
+    fn count_code(events: &[(EventCode, i32)], code: EventCode) -> usize {
+        events.iter().filter(|ev| ev.0 == code).count()
+    }
+
+    fn count(events: &[(EventCode, i32)], event: (EventCode, i32)) -> usize {
+        events.iter().filter(|ev| **ev == event).count()
+    }
+
+    #[test]
+    fn validates_turbo_mode() {
+        assert!(TurboMode::new(0, 50).is_err());
+        assert!(TurboMode::new(31, 50).is_err());
+        assert!(TurboMode::new(10, 0).is_err());
+        assert!(TurboMode::new(10, 100).is_err());
+
+        let phases = TurboMode::new(10, 30).unwrap().phases();
+        assert_eq!(
+            phases,
+            (Duration::from_millis(30), Duration::from_millis(70))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn turbo_lifts_on_release_while_down() {
+        // second tap is down when released
+        let events = turbo(Duration::from_millis(120)).await;
+
+        assert_eq!(count(&events, (TRACKING_ID, 0)), 1);
+        assert_eq!(count(&events, (TRACKING_ID, 1)), 1);
+        assert_eq!(count(&events, (TRACKING_ID, -1)), 2);
+        assert_eq!(events[events.len() - LIFT.len()..], LIFT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn turbo_stays_lifted_on_release_while_up() {
+        // first tap is up when released
+        let events = turbo(Duration::from_millis(70)).await;
+
+        assert_eq!(count(&events, (TRACKING_ID, 0)), 1);
+        assert_eq!(count(&events, (TRACKING_ID, 1)), 0);
+        assert_eq!(count(&events, (TRACKING_ID, -1)), 1);
+        assert_eq!(events[events.len() - LIFT.len()..], LIFT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_lifts_held_turbo() {
+        let (events, _) = hold(&turbo_data(), Duration::from_millis(120), true).await;
+
+        assert_whole_frames(&events);
+        assert_eq!(count(&events, (TRACKING_ID, 1)), 1);
+        assert_eq!(count(&events, (TRACKING_ID, -1)), 2);
+        assert_eq!(events[events.len() - LIFT.len()..], LIFT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_stops_swipe() {
+        let data = GameKeyData {
+            action: Some(Action::Swipe {
+                path: MotionPath {
+                    from: (100, 100),
+                    to: (900, 100),
+                    duration: Duration::from_secs(1),
+                    curve: Curve::Linear,
+                },
+                hold: true,
+            }),
+            ..Default::default()
+        };
+
+        let (events, stopped_after) = hold(&data, Duration::from_millis(100), true).await;
+
+        assert_whole_frames(&events);
+        assert!(stopped_after < NEXT_FRAME, "{:?}", stopped_after);
+        // cut off well before the end of the path
+        assert!(count_code(&events, SYN) < 20, "{:?}", events);
+        assert_eq!(count(&events, (TRACKING_ID, -1)), 1);
+        assert_eq!(events[events.len() - LIFT.len()..], LIFT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_stops_macro_despite_finish_policy() {
+        let data = GameKeyData {
+            action: Some(Action::Macro(Macro {
+                steps: vec![
+                    MacroStep::Down {
+                        finger: 0,
+                        x: 100,
+                        y: 100,
+                    },
+                    MacroStep::Down {
+                        finger: 1,
+                        x: 200,
+                        y: 200,
+                    },
+                    MacroStep::Wait(Duration::from_secs(10)),
+                    MacroStep::Up { finger: 0 },
+                    MacroStep::Up { finger: 1 },
+                ],
+                on_release: ReleasePolicy::Finish,
+            })),
+            ..Default::default()
+        };
+
+        let (events, stopped_after) = hold(&data, Duration::from_millis(100), true).await;
+
+        assert_whole_frames(&events);
+        assert!(stopped_after < NEXT_FRAME, "{:?}", stopped_after);
+        assert_eq!(count(&events, (TRACKING_ID, -1)), 2);
+        assert_eq!(
+            events[events.len() - LIFT.len()..],
+            [
+                (SLOT, 1),
+                (TRACKING_ID, -1),
+                (TOUCH, 0),
+                (FINGER, 0),
+                (SYN, 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+// only the binder service has callers
+#![cfg_attr(feature = "local", allow(dead_code))]
+
+use anyhow::bail;
+
+#[cfg(not(feature = "local"))]
+const ALLOWED_CALLERS_PROP: &str = "ro.vendor.gamekeyd.allowed_callers";
+/// Root, system and the GameKeys app.
+#[cfg(not(feature = "local"))]
+const DEFAULT_ALLOWED_CALLERS: &str = "0,1000,org.ingres.gamekeys";
+
+/// Process calling into the daemon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Caller {
+    pub uid: u32,
+    pub pid: i32,
+}
+
+/// Tells who is calling, binder does in the service.
+pub trait CallerContext {
+    fn caller(&self) -> Caller;
+
+    /// Returns the name of process `pid`, the package name for apps.
+    fn process_name(&self, pid: i32) -> Option<String>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedCaller {
+    Uid(u32),
+    /// Matched against the process name of the caller, SELinux only lets the daemon read
+    /// it for the GameKeys app.
+    Process(String),
+}
+
+/// Callers allowed to use the settings service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allowlist {
+    entries: Vec<AllowedCaller>,
+}
+
+/// Context of the binder transaction being handled.
+#[cfg(not(feature = "local"))]
+pub struct BinderCallerContext;
+
+#[cfg(not(feature = "local"))]
+impl CallerContext for BinderCallerContext {
+    fn caller(&self) -> Caller {
+        use gamekeyd_aidl::binder::ThreadState;
+
+        Caller {
+            uid: ThreadState::get_calling_uid(),
+            pid: ThreadState::get_calling_pid(),
+        }
+    }
+
+    fn process_name(&self, pid: i32) -> Option<String> {
+        let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        let name = cmdline.split(|b| *b == 0).next()?;
+
+        String::from_utf8(name.to_vec()).ok()
+    }
+}
+
+impl Allowlist {
+    /// Parses a comma separated list of UIDs and process names.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let entries: Vec<AllowedCaller> = text
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.parse() {
+                Ok(uid) => AllowedCaller::Uid(uid),
+                Err(_) => AllowedCaller::Process(entry.to_string()),
+            })
+            .collect();
+
+        if entries.is_empty() {
+            bail!("Allowlist is empty");
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Reads the allowlist from `ro.vendor.gamekeyd.allowed_callers`, falling back to root,
+    /// system and the GameKeys app.
+    #[cfg(not(feature = "local"))]
+    pub fn from_property() -> Self {
+        let text = match rustutils::system_properties::read(ALLOWED_CALLERS_PROP) {
+            Ok(Some(text)) => text,
+            _ => return Self::parse(DEFAULT_ALLOWED_CALLERS).unwrap(),
+        };
+
+        Self::parse(&text).unwrap_or_else(|e| {
+            log::error!("Ignoring {}: {:#}", ALLOWED_CALLERS_PROP, e);
+            Self::parse(DEFAULT_ALLOWED_CALLERS).unwrap()
+        })
+    }
+
+    /// Returns the caller if it is allowed.
+    pub fn check(&self, context: &impl CallerContext) -> Result<Caller, Caller> {
+        let caller = context.caller();
+
+        if self
+            .entries
+            .iter()
+            .any(|entry| matches!(entry, AllowedCaller::Uid(uid) if *uid == caller.uid))
+        {
+            return Ok(caller);
+        }
+
+        let is_process = |name: &str| {
+            self.entries
+                .iter()
+                .any(|entry| matches!(entry, AllowedCaller::Process(allowed) if allowed == name))
+        };
+
+        match context.process_name(caller.pid) {
+            Some(name) if is_process(&name) => Ok(caller),
+            _ => Err(caller),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeContext {
+        caller: Caller,
+        process: Option<&'static str>,
+    }
+
+    impl CallerContext for FakeContext {
+        fn caller(&self) -> Caller {
+            self.caller
+        }
+
+        fn process_name(&self, pid: i32) -> Option<String> {
+            assert_eq!(pid, self.caller.pid);
+            self.process.map(str::to_string)
+        }
+    }
+
+    fn context(uid: u32, process: Option<&'static str>) -> FakeContext {
+        FakeContext {
+            caller: Caller { uid, pid: 1234 },
+            process,
+        }
+    }
+
+    #[test]
+    fn parses_uids_and_processes() {
+        let allowlist = Allowlist::parse(" 1000, org.example.app,,").unwrap();
+
+        assert_eq!(
+            allowlist.entries,
+            vec![
+                AllowedCaller::Uid(1000),
+                AllowedCaller::Process("org.example.app".to_string())
+            ]
+        );
+        assert!(Allowlist::parse(" , ").is_err());
+    }
+
+    #[test]
+    fn allows_listed_uid() {
+        let allowlist = Allowlist::parse("1000").unwrap();
+
+        assert!(allowlist.check(&context(1000, None)).is_ok());
+    }
+
+    #[test]
+    fn allows_listed_process() {
+        let allowlist = Allowlist::parse("1000,org.example.app").unwrap();
+
+        assert!(allowlist
+            .check(&context(10123, Some("org.example.app")))
+            .is_ok());
+    }
+
+    #[test]
+    fn denies_others() {
+        let allowlist = Allowlist::parse("1000,org.example.app").unwrap();
+
+        assert_eq!(
+            allowlist.check(&context(10124, Some("org.example.other"))),
+            Err(Caller {
+                uid: 10124,
+                pid: 1234
+            })
+        );
+        // unreadable process, e.g. kept from the daemon by SELinux
+        assert!(allowlist.check(&context(10124, None)).is_err());
+    }
+}
@@ -1,18 +1,220 @@
-use crate::GameKeyCompound;
+use crate::action::{Action, TurboMode};
+use crate::auth::{Allowlist, BinderCallerContext};
+use crate::display::Rotation;
+use crate::gamekey::gesture::Gesture;
+use crate::gamekey::EventType;
+use crate::listener::ListenerRegistry;
+use crate::macro_runner::{Macro, MacroStep, ReleasePolicy};
+use crate::settings::{self, SettingsController};
+use crate::status::TriggerState;
+use crate::touch_emulator::{Curve, MotionPath};
+use crate::GameKeyData;
 use async_trait::async_trait;
 use gamekeyd_aidl::{
     aidl::org::ingres::gamekeys::{
+        Action::Action as AidlAction,
+        Curve::Curve as AidlCurve,
+        DaemonStatus::DaemonStatus,
+        GameKeyEventKind::GameKeyEventKind,
+        Gesture::Gesture as AidlGesture,
+        IGameKeyListener::IGameKeyListener,
         ISettingsService::{self, ISettingsServiceAsyncServer, ISettingsServiceDefaultRef},
+        InputDeviceInfo::InputDeviceInfo,
+        Macro::Macro as AidlMacro,
+        MacroStep::MacroStep as AidlMacroStep,
+        MacroStepKind::MacroStepKind,
         Point::Point,
+        Rotation::Rotation as AidlRotation,
+        Settings::Settings,
+        Swipe::Swipe,
+        TriggerState::TriggerState as AidlTriggerState,
+        TurboMode::TurboMode as AidlTurboMode,
     },
-    binder::{Interface, Result},
+    binder::{ExceptionCode, Interface, Result, Status, StatusCode, Strong},
 };
+use std::ffi::CStr;
+use std::io::Write;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
 
-pub struct SettingsService(Arc<RwLock<GameKeyCompound>>);
+pub struct SettingsService {
+    controller: Arc<SettingsController>,
+    listeners: Arc<ListenerRegistry>,
+    allowlist: Allowlist,
+}
+
+/// Shell, which `dumpsys` runs as.
+const AID_SHELL: u32 = 2000;
+const DEBUGGABLE_PROP: &str = "ro.debuggable";
+
+impl Interface for SettingsService {
+    fn dump(&self, writer: &mut dyn Write, _args: &[&CStr]) -> StdResult<(), StatusCode> {
+        if let Err(caller) = self.allowlist.check(&BinderCallerContext) {
+            if caller.uid != AID_SHELL {
+                return Err(StatusCode::PERMISSION_DENIED);
+            }
+        }
+
+        // binder threads are outside the runtime, blocking is fine
+        self.controller
+            .write_dump(writer)
+            .map_err(|_| StatusCode::UNKNOWN_ERROR)
+    }
+}
+
+/// Whether this is an eng or userdebug build, a property that can't be read counts as no.
+fn is_debuggable() -> bool {
+    matches!(
+        rustutils::system_properties::read(DEBUGGABLE_PROP),
+        Ok(Some(value)) if value == "1"
+    )
+}
+
+fn illegal_argument(msg: &str) -> Status {
+    Status::new_exception_str(ExceptionCode::ILLEGAL_ARGUMENT, Some(msg))
+}
+
+fn to_status(e: settings::Error) -> Status {
+    match e {
+        settings::Error::InvalidArgument(msg) => illegal_argument(&msg),
+        settings::Error::EventLoopGone => {
+            Status::new_exception_str(ExceptionCode::ILLEGAL_STATE, Some("event loop is gone"))
+        }
+    }
+}
+
+fn to_position(point: &Point) -> (i32, i32) {
+    (point.x, point.y)
+}
+
+fn tap_point(data: &GameKeyData) -> Option<Point> {
+    match data.action {
+        Some(Action::Tap { x, y }) => Some(Point { x, y }),
+        _ => None,
+    }
+}
 
-impl Interface for SettingsService {}
+fn to_event_type(kind: GameKeyEventKind) -> Result<EventType> {
+    match kind {
+        GameKeyEventKind::OPEN => Ok(EventType::Open),
+        GameKeyEventKind::CLOSE => Ok(EventType::Close),
+        GameKeyEventKind::PRESS => Ok(EventType::Press),
+        GameKeyEventKind::RELEASE => Ok(EventType::Release),
+        _ => Err(illegal_argument("unknown event kind")),
+    }
+}
+
+fn to_rotation(rotation: AidlRotation) -> Result<Rotation> {
+    match rotation {
+        AidlRotation::ROTATION_0 => Ok(Rotation::Rotation0),
+        AidlRotation::ROTATION_90 => Ok(Rotation::Rotation90),
+        AidlRotation::ROTATION_180 => Ok(Rotation::Rotation180),
+        AidlRotation::ROTATION_270 => Ok(Rotation::Rotation270),
+        _ => Err(illegal_argument("unknown rotation")),
+    }
+}
+
+fn to_trigger_state(state: TriggerState) -> AidlTriggerState {
+    match state {
+        TriggerState::Unknown => AidlTriggerState::UNKNOWN,
+        TriggerState::Open => AidlTriggerState::OPEN,
+        TriggerState::Closed => AidlTriggerState::CLOSED,
+    }
+}
+
+fn to_curve(curve: AidlCurve) -> Result<Curve> {
+    match curve {
+        AidlCurve::LINEAR => Ok(Curve::Linear),
+        AidlCurve::EASE_IN => Ok(Curve::EaseIn),
+        AidlCurve::EASE_OUT => Ok(Curve::EaseOut),
+        AidlCurve::EASE_IN_OUT => Ok(Curve::EaseInOut),
+        _ => Err(illegal_argument("unknown curve")),
+    }
+}
+
+fn to_swipe_action(swipe: &Swipe) -> Result<Action> {
+    Ok(Action::Swipe {
+        path: MotionPath {
+            from: to_position(&swipe.from),
+            to: to_position(&swipe.to),
+            duration: to_duration(swipe.durationMs)?,
+            curve: to_curve(swipe.curve)?,
+        },
+        hold: swipe.hold,
+    })
+}
+
+/// Leaves validation to the controller, negative values are as invalid as zero.
+fn to_turbo_mode(turbo: &AidlTurboMode) -> TurboMode {
+    TurboMode {
+        taps_per_second: u32::try_from(turbo.tapsPerSecond).unwrap_or(0),
+        duty_cycle: u32::try_from(turbo.dutyCycle).unwrap_or(0),
+    }
+}
+
+fn to_duration(ms: i32) -> Result<Duration> {
+    u64::try_from(ms)
+        .map(Duration::from_millis)
+        .map_err(|_| illegal_argument("negative duration"))
+}
+
+fn to_macro_steps(steps: &[AidlMacroStep]) -> Result<Vec<MacroStep>> {
+    steps
+        .iter()
+        .map(|step| {
+            let finger =
+                usize::try_from(step.finger).map_err(|_| illegal_argument("invalid finger"))?;
+            let (x, y) = to_position(&step.point);
+
+            Ok(match step.kind {
+                MacroStepKind::DOWN => MacroStep::Down { finger, x, y },
+                MacroStepKind::MOVE => MacroStep::Move { finger, x, y },
+                MacroStepKind::UP => MacroStep::Up { finger },
+                MacroStepKind::WAIT => MacroStep::Wait(to_duration(step.durationMs)?),
+                MacroStepKind::PARALLEL => MacroStep::Parallel(
+                    step.branches
+                        .iter()
+                        .map(|branch| to_macro_steps(&branch.steps))
+                        .collect::<Result<_>>()?,
+                ),
+                _ => return Err(illegal_argument("unknown macro step")),
+            })
+        })
+        .collect()
+}
+
+fn to_macro_action(macro_: &AidlMacro) -> Result<Action> {
+    Ok(Action::Macro(Macro {
+        steps: to_macro_steps(&macro_.steps)?,
+        on_release: if macro_.finishOnRelease {
+            ReleasePolicy::Finish
+        } else {
+            ReleasePolicy::Cancel
+        },
+    }))
+}
+
+fn to_gesture(gesture: AidlGesture) -> Result<Gesture> {
+    match gesture {
+        AidlGesture::PRESS => Ok(Gesture::Press),
+        AidlGesture::LONG_PRESS => Ok(Gesture::LongPress),
+        AidlGesture::DOUBLE_PRESS => Ok(Gesture::DoublePress),
+        _ => Err(illegal_argument("unknown gesture")),
+    }
+}
+
+fn to_action(action: &AidlAction) -> Result<Action> {
+    match action {
+        AidlAction::Tap(point) => {
+            let (x, y) = to_position(point);
+            Ok(Action::Tap { x, y })
+        }
+        AidlAction::Swipe(swipe) => to_swipe_action(swipe),
+        AidlAction::Macro(macro_) => to_macro_action(macro_),
+    }
+}
 
 #[allow(non_snake_case)]
 #[async_trait]
@@ -22,16 +224,247 @@ impl ISettingsServiceAsyncServer for SettingsService {
         upper: Option<&'l1 Point>,
         lower: Option<&'l2 Point>,
     ) -> Result<()> {
-        let mut compound = self.0.write().await;
-        compound.upper = upper.map(|point| (point.x * 10, point.y * 10));
-        compound.lower = lower.map(|point| (point.x * 10, point.y * 10));
+        self.authorize()?;
+
+        self.controller
+            .set_taps(upper.map(to_position), lower.map(to_position))
+            .await;
+
+        Ok(())
+    }
+
+    async fn r#getSettings<'a>(&'a self) -> Result<Settings> {
+        self.authorize()?;
+
+        let compound = self.controller.settings().await;
+
+        Ok(Settings {
+            upper: tap_point(&compound.upper),
+            lower: tap_point(&compound.lower),
+            upperEnabled: compound.upper.is_enabled(),
+            lowerEnabled: compound.lower.is_enabled(),
+        })
+    }
+
+    async fn r#setSwipe<'a, 'l1>(&'a self, slot: i32, swipe: Option<&'l1 Swipe>) -> Result<()> {
+        self.authorize()?;
+
+        let action = swipe.map(to_swipe_action).transpose()?;
+
+        self.controller
+            .set_action(slot, Gesture::Press, action)
+            .await
+            .map_err(to_status)
+    }
+
+    async fn r#setTurbo<'a, 'l1>(
+        &'a self,
+        slot: i32,
+        turbo: Option<&'l1 AidlTurboMode>,
+    ) -> Result<()> {
+        self.authorize()?;
+
+        self.controller
+            .set_turbo(slot, turbo.map(to_turbo_mode))
+            .await
+            .map_err(to_status)
+    }
+
+    async fn r#setMacro<'a, 'l1>(
+        &'a self,
+        slot: i32,
+        macro_: Option<&'l1 AidlMacro>,
+    ) -> Result<()> {
+        self.authorize()?;
+
+        let action = macro_.map(to_macro_action).transpose()?;
+
+        self.controller
+            .set_action(slot, Gesture::Press, action)
+            .await
+            .map_err(to_status)
+    }
+
+    async fn r#setGestureAction<'a, 'l1>(
+        &'a self,
+        slot: i32,
+        gesture: AidlGesture,
+        action: Option<&'l1 AidlAction>,
+    ) -> Result<()> {
+        self.authorize()?;
+
+        let gesture = to_gesture(gesture)?;
+        let action = action.map(to_action).transpose()?;
+
+        self.controller
+            .set_action(slot, gesture, action)
+            .await
+            .map_err(to_status)
+    }
+
+    async fn r#setGestureTiming<'a>(&'a self, longPressMs: i32, doublePressMs: i32) -> Result<()> {
+        self.authorize()?;
+
+        self.controller
+            .set_gesture_timing(to_duration(longPressMs)?, to_duration(doublePressMs)?)
+            .await
+            .map_err(to_status)
+    }
+
+    async fn r#setChordWindow<'a>(&'a self, windowMs: i32) -> Result<()> {
+        self.authorize()?;
+
+        self.controller
+            .set_chord_window(to_duration(windowMs)?)
+            .await
+            .map_err(to_status)
+    }
+
+    async fn r#activateProfile<'a, 'l1>(&'a self, packageName: &'l1 str) -> Result<()> {
+        self.authorize()?;
+
+        self.controller.activate_profile(packageName).await;
 
         Ok(())
     }
+
+    async fn r#removeProfile<'a, 'l1>(&'a self, packageName: &'l1 str) -> Result<()> {
+        self.authorize()?;
+
+        self.controller.remove_profile(packageName).await;
+
+        Ok(())
+    }
+
+    async fn r#getProfiles<'a>(&'a self) -> Result<Vec<String>> {
+        self.authorize()?;
+
+        Ok(self.controller.profiles().await)
+    }
+
+    async fn r#getStatus<'a>(&'a self) -> Result<DaemonStatus> {
+        self.authorize()?;
+
+        let (status, active_slots) = self.controller.status().await;
+        let path = |path: Option<PathBuf>| path.map(|p| p.display().to_string());
+
+        Ok(DaemonStatus {
+            inputDevices: status
+                .input_devices
+                .into_iter()
+                .map(|(path, name)| InputDeviceInfo {
+                    path: path.display().to_string(),
+                    name,
+                })
+                .collect(),
+            gamekeyDevice: path(status.gamekey_device),
+            touchDevice: path(status.touch_device),
+            touchGrabbed: status.touch_grabbed,
+            mergedDevice: status.merged_device,
+            triggers: status.triggers.into_iter().map(to_trigger_state).collect(),
+            activeSlots: active_slots.into_iter().map(|slot| slot as i32).collect(),
+        })
+    }
+
+    async fn r#registerListener<'a, 'l1>(
+        &'a self,
+        listener: &'l1 Strong<dyn IGameKeyListener>,
+    ) -> Result<()> {
+        self.authorize()?;
+
+        self.listeners.register(listener)?;
+
+        Ok(())
+    }
+
+    async fn r#unregisterListener<'a, 'l1>(
+        &'a self,
+        listener: &'l1 Strong<dyn IGameKeyListener>,
+    ) -> Result<()> {
+        self.authorize()?;
+
+        self.listeners.unregister(listener);
+
+        Ok(())
+    }
+
+    async fn r#setDisplay<'a>(
+        &'a self,
+        width: i32,
+        height: i32,
+        rotation: AidlRotation,
+    ) -> Result<()> {
+        self.authorize()?;
+
+        self.controller
+            .set_display(width, height, to_rotation(rotation)?)
+            .await
+            .map_err(to_status)
+    }
+
+    async fn r#setRotation<'a>(&'a self, rotation: AidlRotation) -> Result<()> {
+        self.authorize()?;
+
+        self.controller.set_rotation(to_rotation(rotation)?).await;
+
+        Ok(())
+    }
+
+    async fn r#setPaused<'a>(&'a self, paused: bool) -> Result<()> {
+        self.authorize()?;
+
+        self.controller.set_paused(paused);
+
+        Ok(())
+    }
+
+    async fn r#setSlotPaused<'a>(&'a self, slot: i32, paused: bool) -> Result<()> {
+        self.authorize()?;
+
+        self.controller
+            .set_slot_paused(slot, paused)
+            .map_err(to_status)
+    }
+
+    async fn r#injectEvent<'a>(&'a self, kind: GameKeyEventKind, slot: i32) -> Result<()> {
+        self.authorize()?;
+
+        // allowlisted apps must not press the triggers on user builds
+        if !is_debuggable() {
+            return Err(Status::new_exception_str(
+                ExceptionCode::SECURITY,
+                Some("injectEvent needs a debuggable build"),
+            ));
+        }
+
+        self.controller
+            .inject_event(to_event_type(kind)?, slot)
+            .await
+            .map_err(to_status)
+    }
 }
 
 impl SettingsService {
-    pub fn new(compound: Arc<RwLock<GameKeyCompound>>) -> Self {
-        Self { 0: compound }
+    pub fn new(
+        controller: Arc<SettingsController>,
+        listeners: Arc<ListenerRegistry>,
+        allowlist: Allowlist,
+    ) -> Self {
+        Self {
+            controller,
+            listeners,
+            allowlist,
+        }
+    }
+
+    /// Fails with a security exception unless the caller is on the allowlist.
+    fn authorize(&self) -> Result<()> {
+        self.allowlist
+            .check(&BinderCallerContext)
+            .map(|_| ())
+            .map_err(|caller| {
+                log::warn!("Denied call from uid {} pid {}", caller.uid, caller.pid);
+                Status::new_exception_str(ExceptionCode::SECURITY, Some("caller not allowed"))
+            })
     }
 }
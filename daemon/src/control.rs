@@ -0,0 +1,399 @@
+use crate::action::{Action, TurboMode};
+use crate::display::Rotation;
+use crate::gamekey::gesture::Gesture;
+use crate::gamekey::{Event, EventType};
+use crate::settings::SettingsController;
+use crate::status::DaemonStatus;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Socket in the data directory the `local` build takes requests on.
+pub const CONTROL_SOCKET: &str = "control.sock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+/// One line of JSON sent to the control socket, the operations of `ISettingsService`.
+///
+/// Actions and turbo modes are written as in `settings.json`, `setSwipe` and `setMacro` are
+/// `setGestureAction` with the `press` gesture.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum Request {
+    SetSettings {
+        upper: Option<Point>,
+        lower: Option<Point>,
+    },
+    GetSettings,
+    SetGestureAction {
+        slot: i32,
+        gesture: Gesture,
+        action: Option<Action>,
+    },
+    SetTurbo {
+        slot: i32,
+        turbo: Option<TurboMode>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetGestureTiming {
+        long_press_ms: u64,
+        double_press_ms: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetChordWindow {
+        window_ms: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    ActivateProfile {
+        package_name: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    RemoveProfile {
+        package_name: String,
+    },
+    GetProfiles,
+    GetStatus,
+    SetDisplay {
+        width: i32,
+        height: i32,
+        rotation: Rotation,
+    },
+    SetRotation {
+        rotation: Rotation,
+    },
+    SetPaused {
+        paused: bool,
+    },
+    SetSlotPaused {
+        slot: i32,
+        paused: bool,
+    },
+    InjectEvent {
+        kind: EventType,
+        slot: i32,
+    },
+    /// Turns the connection into a stream of gamekey events, like registering a listener.
+    Subscribe,
+    Dump,
+}
+
+fn response(result: anyhow::Result<Value>) -> Value {
+    match result {
+        Ok(result) => json!({ "ok": true, "result": result }),
+        Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+    }
+}
+
+fn status_json(status: &DaemonStatus, active_slots: &[usize]) -> Value {
+    json!({
+        "inputDevices": status
+            .input_devices
+            .iter()
+            .map(|(path, name)| json!({ "path": path, "name": name }))
+            .collect::<Vec<_>>(),
+        "gamekeyDevice": status.gamekey_device,
+        "touchDevice": status.touch_device,
+        "touchGrabbed": status.touch_grabbed,
+        "mergedDevice": status.merged_device,
+        "triggers": status.triggers,
+        "activeSlots": active_slots,
+    })
+}
+
+fn event_json(ev: &Event) -> Value {
+    json!({
+        "kind": ev.r#type,
+        "slot": ev.slot,
+        "time": { "sec": ev.time.tv_sec, "usec": ev.time.tv_usec },
+    })
+}
+
+async fn handle(controller: &Arc<SettingsController>, request: Request) -> anyhow::Result<Value> {
+    match request {
+        Request::SetSettings { upper, lower } => {
+            let position = |point: Point| (point.x, point.y);
+
+            controller
+                .set_taps(upper.map(position), lower.map(position))
+                .await;
+        }
+        Request::GetSettings => return Ok(serde_json::to_value(controller.settings().await)?),
+        Request::SetGestureAction {
+            slot,
+            gesture,
+            action,
+        } => controller.set_action(slot, gesture, action).await?,
+        Request::SetTurbo { slot, turbo } => controller.set_turbo(slot, turbo).await?,
+        Request::SetGestureTiming {
+            long_press_ms,
+            double_press_ms,
+        } => {
+            controller
+                .set_gesture_timing(
+                    Duration::from_millis(long_press_ms),
+                    Duration::from_millis(double_press_ms),
+                )
+                .await?
+        }
+        Request::SetChordWindow { window_ms } => {
+            controller
+                .set_chord_window(Duration::from_millis(window_ms))
+                .await?
+        }
+        Request::ActivateProfile { package_name } => {
+            controller.activate_profile(&package_name).await
+        }
+        Request::RemoveProfile { package_name } => controller.remove_profile(&package_name).await,
+        Request::GetProfiles => return Ok(json!(controller.profiles().await)),
+        Request::GetStatus => {
+            let (status, active_slots) = controller.status().await;
+
+            return Ok(status_json(&status, &active_slots));
+        }
+        Request::SetDisplay {
+            width,
+            height,
+            rotation,
+        } => controller.set_display(width, height, rotation).await?,
+        Request::SetRotation { rotation } => controller.set_rotation(rotation).await,
+        Request::SetPaused { paused } => controller.set_paused(paused),
+        Request::SetSlotPaused { slot, paused } => controller.set_slot_paused(slot, paused)?,
+        Request::InjectEvent { kind, slot } => controller.inject_event(kind, slot).await?,
+        // the connection streams events instead of answering
+        Request::Subscribe => bail!("Subscriptions can't be handled as a request"),
+        Request::Dump => {
+            let controller = controller.clone();
+            let text = tokio::task::spawn_blocking(move || {
+                let mut out = Vec::new();
+                controller.write_dump(&mut out).map(|_| out)
+            })
+            .await??;
+
+            return Ok(json!(String::from_utf8_lossy(&text)));
+        }
+    }
+
+    Ok(Value::Null)
+}
+
+async fn write_line(out: &mut (impl AsyncWrite + Unpin), value: &Value) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+
+    out.write_all(&line).await?;
+
+    Ok(())
+}
+
+async fn stream_events(
+    out: &mut (impl AsyncWrite + Unpin),
+    mut events: broadcast::Receiver<Event>,
+) -> anyhow::Result<()> {
+    write_line(out, &response(Ok(Value::Null))).await?;
+
+    loop {
+        match events.recv().await {
+            Ok(ev) => write_line(out, &event_json(&ev)).await?,
+            Err(RecvError::Lagged(count)) => log::warn!("Subscriber missed {} events", count),
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn serve_client(
+    stream: UnixStream,
+    controller: Arc<SettingsController>,
+    events: broadcast::Sender<Event>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match serde_json::from_str(&line) {
+            Ok(Request::Subscribe) => return stream_events(&mut writer, events.subscribe()).await,
+            Ok(request) => handle(&controller, request).await,
+            Err(e) => Err(anyhow::Error::new(e).context("Invalid request")),
+        };
+
+        write_line(&mut writer, &response(result)).await?;
+    }
+
+    Ok(())
+}
+
+/// Takes requests on `path`, one line of JSON each answered by one line of JSON.
+pub async fn serve(
+    path: &Path,
+    controller: Arc<SettingsController>,
+    events: broadcast::Sender<Event>,
+) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    // left behind by the last run
+    let _ = std::fs::remove_file(path);
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    log::info!("Control socket listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let controller = controller.clone();
+        let events = events.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_client(stream, controller, events).await {
+                log::warn!("Control connection failed: {:#}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::ProfileStore;
+    use crate::status::SharedStatus;
+    use crate::storage::SettingsStorage;
+    use crate::touch_emulator::TouchEmulator;
+    use crate::utils::clock::Clock;
+    use crate::utils::temp_dir::TempDir;
+    use crate::{GameKeyCompound, PauseState};
+    use tokio::sync::{mpsc, watch, Mutex, RwLock};
+
+    async fn call(stream: &mut BufReader<UnixStream>, request: &str) -> Value {
+        stream
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+        stream.get_mut().write_all(b"\n").await.unwrap();
+
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn parses_requests() {
+        let request: Request =
+            serde_json::from_str(r#"{"op":"setSettings","upper":{"x":10,"y":20}}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::SetSettings {
+                upper: Some(Point { x: 10, y: 20 }),
+                lower: None,
+            }
+        );
+
+        let request: Request = serde_json::from_str(
+            r#"{"op":"setGestureAction","slot":1,"gesture":"longPress","action":{"Tap":{"x":1,"y":2}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request::SetGestureAction {
+                slot: 1,
+                gesture: Gesture::LongPress,
+                action: Some(Action::Tap { x: 1, y: 2 }),
+            }
+        );
+
+        let request: Request = serde_json::from_str(
+            r#"{"op":"setGestureTiming","longPressMs":500,"doublePressMs":200}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request::SetGestureTiming {
+                long_press_ms: 500,
+                double_press_ms: 200,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        assert!(serde_json::from_str::<Request>(r#"{"op":"reboot"}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"op":"setPaused"}"#).is_err());
+        assert!(
+            serde_json::from_str::<Request>(r#"{"op":"setRotation","rotation":"sideways"}"#)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_requests() {
+        let dir = TempDir::new("control");
+        let path = dir.join(CONTROL_SOCKET);
+
+        let (emulator, _emulator_rx) = TouchEmulator::new(1, Clock::default()).unwrap();
+        let (pause, _paused) = watch::channel(PauseState::default());
+        let (inject, mut injected) = mpsc::channel(1);
+        let controller = Arc::new(SettingsController::new(
+            Arc::new(RwLock::new(GameKeyCompound::default())),
+            Arc::new(Mutex::new(ProfileStore::default())),
+            SettingsStorage::new(&*dir),
+            SharedStatus::default(),
+            Arc::new(Mutex::new(emulator)),
+            pause,
+            inject,
+        ));
+        let (events, _) = broadcast::channel(1);
+
+        tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, controller, events).await }
+        });
+
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        let mut stream = BufReader::new(stream);
+
+        let reply = call(&mut stream, r#"{"op":"setSettings","lower":{"x":5,"y":6}}"#).await;
+        assert_eq!(reply, json!({ "ok": true, "result": null }));
+
+        let reply = call(&mut stream, r#"{"op":"getSettings"}"#).await;
+        assert_eq!(
+            reply["result"]["lower"]["action"],
+            json!({ "Tap": { "x": 5, "y": 6 } })
+        );
+
+        let reply = call(&mut stream, r#"{"op":"setTurbo","slot":7,"turbo":null}"#).await;
+        assert_eq!(reply["ok"], json!(false));
+
+        let reply = call(
+            &mut stream,
+            r#"{"op":"injectEvent","kind":"press","slot":1}"#,
+        )
+        .await;
+        assert_eq!(reply["ok"], json!(true));
+        let ev = injected.recv().await.unwrap();
+        assert_eq!((ev.r#type, ev.slot), (EventType::Press, 1));
+
+        let reply = call(&mut stream, "not json").await;
+        assert_eq!(reply["ok"], json!(false));
+    }
+}
@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+
+/// Rotation of the display from its natural orientation, as in `Surface.ROTATION_*`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Rotation {
+    #[default]
+    Rotation0,
+    Rotation90,
+    Rotation180,
+    Rotation270,
+}
+
+/// Maps points of the display, as the foreground app sees it, onto the touch panel.
+///
+/// Bound points are kept in display pixels and only mapped when touched, so they stay on
+/// the same spot of the app after the display rotates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayTransform {
+    /// Size of the display in its natural orientation.
+    natural: (i32, i32),
+    rotation: Rotation,
+    /// Minimum and maximum of `ABS_MT_POSITION_X` of the panel.
+    panel_x: (i32, i32),
+    /// Minimum and maximum of `ABS_MT_POSITION_Y` of the panel.
+    panel_y: (i32, i32),
+}
+
+/// The display as last declared, kept with the settings so bound points land on the same
+/// spot after a restart.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DisplayConfig {
+    /// Size of the display in its natural orientation.
+    pub width: i32,
+    pub height: i32,
+    pub rotation: Rotation,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidSize,
+    InvalidPanelRange,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidSize => write!(f, "Invalid display size!"),
+            Error::InvalidPanelRange => write!(f, "Invalid panel range!"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Rotation {
+    fn is_sideways(&self) -> bool {
+        matches!(self, Rotation::Rotation90 | Rotation::Rotation270)
+    }
+}
+
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        Self {
+            natural: (1080, 2400),
+            rotation: Rotation::Rotation0,
+            panel_x: (0, 10799),
+            panel_y: (0, 23999),
+        }
+    }
+}
+
+impl DisplayTransform {
+    /// Declares the display as the app currently sees it, `width` and `height` are taken
+    /// after `rotation`.
+    pub fn set_display(
+        &mut self,
+        width: i32,
+        height: i32,
+        rotation: Rotation,
+    ) -> Result<(), Error> {
+        if width <= 0 || height <= 0 {
+            return Err(Error::InvalidSize);
+        }
+
+        self.natural = if rotation.is_sideways() {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        self.rotation = rotation;
+
+        Ok(())
+    }
+
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.rotation = rotation;
+    }
+
+    pub fn config(&self) -> DisplayConfig {
+        DisplayConfig {
+            width: self.natural.0,
+            height: self.natural.1,
+            rotation: self.rotation,
+        }
+    }
+
+    /// Restores a display returned by [`Self::config`].
+    pub fn set_config(&mut self, config: &DisplayConfig) -> Result<(), Error> {
+        if config.width <= 0 || config.height <= 0 {
+            return Err(Error::InvalidSize);
+        }
+
+        self.natural = (config.width, config.height);
+        self.rotation = config.rotation;
+
+        Ok(())
+    }
+
+    /// Sets the ranges of the panel axes, as reported in its `abs_info`.
+    pub fn set_panel(&mut self, x: (i32, i32), y: (i32, i32)) -> Result<(), Error> {
+        if x.0 >= x.1 || y.0 >= y.1 {
+            return Err(Error::InvalidPanelRange);
+        }
+
+        self.panel_x = x;
+        self.panel_y = y;
+
+        Ok(())
+    }
+
+    /// Maps a point of the rotated display onto the panel, clamped to its range.
+    pub fn to_panel(self, x: i32, y: i32) -> (i32, i32) {
+        let (width, height) = self.natural;
+
+        let (x, y) = match self.rotation {
+            Rotation::Rotation0 => (x, y),
+            Rotation::Rotation90 => (width - 1 - y, x),
+            Rotation::Rotation180 => (width - 1 - x, height - 1 - y),
+            Rotation::Rotation270 => (y, height - 1 - x),
+        };
+
+        let scale = |value: i32, size: i32, (min, max): (i32, i32)| {
+            let value = min as i64 + value as i64 * (max as i64 - min as i64 + 1) / size as i64;
+            value.clamp(min as i64, max as i64) as i32
+        };
+
+        (
+            scale(x, width, self.panel_x),
+            scale(y, height, self.panel_y),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(width: i32, height: i32, rotation: Rotation) -> DisplayTransform {
+        let mut transform = DisplayTransform {
+            natural: (0, 0),
+            rotation: Rotation::Rotation0,
+            panel_x: (0, 999),
+            panel_y: (0, 1999),
+        };
+        transform.set_display(width, height, rotation).unwrap();
+
+        transform
+    }
+
+    #[test]
+    fn rotation_0() {
+        let transform = transform(100, 200, Rotation::Rotation0);
+
+        assert_eq!(transform.to_panel(0, 0), (0, 0));
+        assert_eq!(transform.to_panel(99, 0), (990, 0));
+        assert_eq!(transform.to_panel(10, 150), (100, 1500));
+    }
+
+    #[test]
+    fn rotation_90() {
+        let transform = transform(200, 100, Rotation::Rotation90);
+
+        // top left of the landscape display is the top right of the panel
+        assert_eq!(transform.to_panel(0, 0), (990, 0));
+        assert_eq!(transform.to_panel(199, 99), (0, 1990));
+        assert_eq!(transform.to_panel(150, 10), (890, 1500));
+    }
+
+    #[test]
+    fn rotation_180() {
+        let transform = transform(100, 200, Rotation::Rotation180);
+
+        assert_eq!(transform.to_panel(0, 0), (990, 1990));
+        assert_eq!(transform.to_panel(99, 199), (0, 0));
+        assert_eq!(transform.to_panel(10, 150), (890, 490));
+    }
+
+    #[test]
+    fn rotation_270() {
+        let transform = transform(200, 100, Rotation::Rotation270);
+
+        // top left of the landscape display is the bottom left of the panel
+        assert_eq!(transform.to_panel(0, 0), (0, 1990));
+        assert_eq!(transform.to_panel(199, 99), (990, 0));
+        assert_eq!(transform.to_panel(150, 10), (100, 490));
+    }
+
+    #[test]
+    fn rotating_keeps_natural_size() {
+        let mut transform = transform(200, 100, Rotation::Rotation90);
+        transform.set_rotation(Rotation::Rotation0);
+
+        assert_eq!(transform.to_panel(99, 199), (990, 1990));
+    }
+
+    #[test]
+    fn clamps_to_panel() {
+        let transform = transform(100, 200, Rotation::Rotation0);
+
+        assert_eq!(transform.to_panel(-5, 500), (0, 1999));
+    }
+
+    #[test]
+    fn offsets_by_panel_minimum() {
+        let mut transform = transform(100, 200, Rotation::Rotation0);
+        transform.set_panel((-500, 499), (100, 2099)).unwrap();
+
+        assert_eq!(transform.to_panel(0, 0), (-500, 100));
+        assert_eq!(transform.to_panel(50, 100), (0, 1100));
+    }
+
+    #[test]
+    fn restores_config() {
+        let saved = transform(200, 100, Rotation::Rotation90);
+        let mut restored = transform(100, 200, Rotation::Rotation0);
+        restored.set_config(&saved.config()).unwrap();
+
+        assert_eq!(restored, saved);
+        assert!(restored
+            .set_config(&DisplayConfig {
+                width: -1,
+                height: 100,
+                rotation: Rotation::Rotation0,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_empty_display() {
+        assert!(DisplayTransform::default()
+            .set_display(0, 100, Rotation::Rotation0)
+            .is_err());
+        assert!(DisplayTransform::default()
+            .set_panel((0, 0), (0, 100))
+            .is_err());
+    }
+}
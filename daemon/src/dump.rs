@@ -0,0 +1,213 @@
+use crate::status::DaemonStatus;
+use crate::{GameKeyCompound, GameKeyData, PauseState};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Everything `dumpsys` prints, collected by the caller so formatting needs no locks.
+pub struct DumpState<'a> {
+    pub compound: &'a GameKeyCompound,
+    pub profile: Option<&'a str>,
+    pub paused: &'a PauseState,
+    pub status: &'a DaemonStatus,
+    pub active_slots: &'a [usize],
+}
+
+fn write_binding(out: &mut dyn Write, name: &str, data: &GameKeyData) -> io::Result<()> {
+    writeln!(out, "  {}:", name)?;
+    writeln!(out, "    press: {:?}", data.action)?;
+    writeln!(out, "    long press: {:?}", data.long_press)?;
+    writeln!(out, "    double press: {:?}", data.double_press)?;
+    writeln!(out, "    turbo: {:?}", data.turbo)
+}
+
+fn path(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map_or("none".to_string(), |p| p.display().to_string())
+}
+
+pub fn write_dump(out: &mut dyn Write, state: &DumpState) -> io::Result<()> {
+    let compound = state.compound;
+    let status = state.status;
+
+    writeln!(
+        out,
+        "Bindings (profile {}):",
+        state.profile.unwrap_or("none")
+    )?;
+    write_binding(out, "upper", &compound.upper)?;
+    write_binding(out, "lower", &compound.lower)?;
+    write_binding(out, "chord", &compound.chord)?;
+    writeln!(
+        out,
+        "  timing: long press {:?}, double press {:?}, chord {:?}",
+        compound.gestures.long_press, compound.gestures.double_press, compound.gestures.chord
+    )?;
+    writeln!(
+        out,
+        "Paused: all {}, slots {:?}",
+        state.paused.all, state.paused.slots
+    )?;
+
+    writeln!(out, "Devices:")?;
+    writeln!(out, "  gamekey: {}", path(&status.gamekey_device))?;
+    writeln!(
+        out,
+        "  touch: {}{}",
+        path(&status.touch_device),
+        if status.touch_grabbed {
+            " (grabbed)"
+        } else {
+            ""
+        }
+    )?;
+    writeln!(
+        out,
+        "  merged: {}",
+        status.merged_device.as_deref().unwrap_or("none")
+    )?;
+    writeln!(out, "Triggers: {:?}", status.triggers)?;
+    writeln!(out, "Emulator active slots: {:?}", state.active_slots)?;
+
+    writeln!(
+        out,
+        "Merger: current slot {}, next tracking id {}",
+        status.merger.current_slot, status.merger.next_tracking_id
+    )?;
+    for (slot, contact) in &status.merger.contacts {
+        writeln!(
+            out,
+            "  slot {}: tracking id {:?}, at {},{}, touch {}x{}, tool {}",
+            slot,
+            contact.tracking_id,
+            contact.x,
+            contact.y,
+            contact.touch_major,
+            contact.touch_minor,
+            contact.tool_type
+        )?;
+    }
+    for source in &status.merger.sources {
+        writeln!(
+            out,
+            "  source {}: slots {}..{}, slot {}, {}, {} events buffered",
+            source.id,
+            source.first_slot,
+            source.first_slot + source.slot_count,
+            source.current_slot,
+            if source.in_touch { "touching" } else { "idle" },
+            source.buffered
+        )?;
+    }
+
+    writeln!(out, "Recent events:")?;
+    for ev in &status.recent_events {
+        writeln!(
+            out,
+            "  {}.{:06} {:?} slot {}",
+            ev.time.tv_sec, ev.time.tv_usec, ev.r#type, ev.slot
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+    use crate::gamekey::{Event, EventType};
+    use crate::status::{MergerStatus, SourceStatus, TriggerState};
+    use crate::touch_state::SlotState;
+    use evdev_rs::TimeVal;
+
+    fn dump(state: &DumpState) -> String {
+        let mut out = Vec::new();
+        write_dump(&mut out, state).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn prints_state() {
+        let mut compound = GameKeyCompound::default();
+        compound.upper.action = Some(Action::Tap { x: 10, y: 20 });
+
+        let mut status = DaemonStatus {
+            touch_device: Some(PathBuf::from("/dev/input/event2")),
+            touch_grabbed: true,
+            triggers: [TriggerState::Open, TriggerState::Unknown],
+            merger: MergerStatus {
+                sources: vec![SourceStatus {
+                    id: 1,
+                    first_slot: 0,
+                    slot_count: 10,
+                    current_slot: 3,
+                    in_touch: true,
+                    buffered: 0,
+                }],
+                contacts: vec![(
+                    3,
+                    SlotState {
+                        tracking_id: Some(41),
+                        x: 100,
+                        y: 200,
+                        touch_major: 8,
+                        touch_minor: 6,
+                        ..Default::default()
+                    },
+                )],
+                current_slot: 3,
+                next_tracking_id: 42,
+            },
+            ..Default::default()
+        };
+        status.record_event(Event {
+            r#type: EventType::Press,
+            slot: 1,
+            time: TimeVal::new(12, 34),
+        });
+
+        let text = dump(&DumpState {
+            compound: &compound,
+            profile: Some("org.example.game"),
+            paused: &PauseState::default(),
+            status: &status,
+            active_slots: &[0],
+        });
+
+        assert!(text.contains("Bindings (profile org.example.game):"));
+        assert!(text.contains("press: Some(Tap { x: 10, y: 20 })"));
+        assert!(text.contains("touch: /dev/input/event2 (grabbed)"));
+        assert!(text.contains("gamekey: none"));
+        assert!(text.contains("Triggers: [Open, Unknown]"));
+        assert!(text.contains("Emulator active slots: [0]"));
+        assert!(text.contains("next tracking id 42"));
+        assert!(text.contains("slot 3: tracking id Some(41), at 100,200, touch 8x6, tool 0"));
+        assert!(text.contains("source 1: slots 0..10, slot 3, touching, 0 events buffered"));
+        assert!(text.contains("12.000034 Press slot 1"));
+    }
+
+    #[test]
+    fn keeps_latest_events() {
+        let mut status = DaemonStatus::default();
+        for slot in 0..100 {
+            status.record_event(Event {
+                r#type: EventType::Release,
+                slot,
+                time: TimeVal::new(0, 0),
+            });
+        }
+
+        let text = dump(&DumpState {
+            compound: &GameKeyCompound::default(),
+            profile: None,
+            paused: &PauseState::default(),
+            status: &status,
+            active_slots: &[],
+        });
+
+        assert!(text.contains("Release slot 99"));
+        assert!(!text.contains("Release slot 67\n"));
+        assert!(text.contains("Release slot 68\n"));
+    }
+}
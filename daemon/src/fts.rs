@@ -1,18 +1,109 @@
+use crate::utils::clock::Clock;
 use crate::utils::udev::enumerate_devices;
 use anyhow::Context;
-use evdev_rs::{Device, InputEvent, ReadFlag};
+use evdev_rs::enums::{EventCode, EventType, InputProp, EV_ABS, EV_SYN};
+use evdev_rs::{
+    AbsInfo, Device, DeviceWrapper, EventCodeIterator, InputEvent, InputPropIterator, ReadFlag,
+    ReadStatus,
+};
 use nix::errno::Errno;
 use nix::ioctl_write_int;
 use nix::libc::EAGAIN;
 use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use std::fs::OpenOptions;
 use std::os::fd::{AsFd, AsRawFd};
+use std::path::PathBuf;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task;
 
 ioctl_write_int!(eviocgrab, b'E', 0x90);
 
+/// Event types copied onto the merged device, touchscreens report nothing else.
+const COPIED_TYPES: [EventType; 5] = [
+    EventType::EV_SYN,
+    EventType::EV_KEY,
+    EventType::EV_ABS,
+    EventType::EV_MSC,
+    EventType::EV_SW,
+];
+
+/// Everything the touchscreen reports, so the merged device can report the same.
+#[derive(Debug, Clone)]
+pub struct TouchCapabilities {
+    pub x: AbsInfo,
+    pub y: AbsInfo,
+    pub slot_count: i32,
+    pub properties: Vec<InputProp>,
+    /// Every event code of the touchscreen, axes with their range and resolution.
+    pub codes: Vec<(EventCode, Option<AbsInfo>)>,
+}
+
+impl TouchCapabilities {
+    fn from_device(device: &Device) -> anyhow::Result<Self> {
+        let abs_info = |axis| {
+            device
+                .abs_info(&EventCode::EV_ABS(axis))
+                .with_context(|| format!("Touchscreen has no {:?}", axis))
+        };
+        let slots = abs_info(EV_ABS::ABS_MT_SLOT)?;
+
+        Ok(Self {
+            x: abs_info(EV_ABS::ABS_MT_POSITION_X)?,
+            y: abs_info(EV_ABS::ABS_MT_POSITION_Y)?,
+            slot_count: slots.maximum + 1,
+            properties: InputPropIterator::new()
+                .filter(|prop| device.has_property(prop))
+                .collect(),
+            codes: COPIED_TYPES
+                .iter()
+                .filter(|ty| device.has_event_type(ty))
+                .flat_map(EventCodeIterator::new)
+                .filter(|code| device.has_event_code(code))
+                // libevdev looks the code up as an axis whatever its type is
+                .map(|code| match code {
+                    EventCode::EV_ABS(_) => (code, device.abs_info(&code)),
+                    _ => (code, None),
+                })
+                .collect(),
+        })
+    }
+
+    pub fn abs_info(&self, axis: EV_ABS) -> Option<AbsInfo> {
+        self.codes
+            .iter()
+            .find(|(code, _)| *code == EventCode::EV_ABS(axis))
+            .and_then(|(_, info)| *info)
+    }
+}
+
+/// Returns the state libevdev resynthesized after `dropped`, led by `dropped` so the merger
+/// throws away the frame the kernel cut short.
+fn sync_events(device: &Device, dropped: InputEvent) -> Vec<InputEvent> {
+    let mut events = vec![dropped];
+
+    loop {
+        match device.next_event(ReadFlag::SYNC) {
+            Ok((_, ev)) => events.push(ev),
+            Err(e) if e.raw_os_error() == Some(EAGAIN) => break,
+            Err(e) => panic!("Failed to sync fts device: {}", e),
+        }
+    }
+
+    // the delta is applied as one frame
+    if events.last().map(|ev| ev.event_code) != Some(EventCode::EV_SYN(EV_SYN::SYN_REPORT)) {
+        events.push(InputEvent {
+            time: events.last().unwrap().time,
+            event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+            value: 0,
+        });
+    }
+
+    log::warn!("fts device dropped events, resynced {}", events.len() - 2);
+
+    events
+}
+
 fn working_thread(device: Device, tx: Sender<InputEvent>) {
     let fd = device.file().as_fd();
     let mut pfd = [PollFd::new(fd, PollFlags::POLLIN)];
@@ -26,21 +117,27 @@ fn working_thread(device: Device, tx: Sender<InputEvent>) {
         }
 
         loop {
-            let ev = match device.next_event(ReadFlag::BLOCKING) {
-                Ok((_, ev)) => ev,
+            let events = match device.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING) {
+                Ok((ReadStatus::Sync, ev)) => sync_events(&device, ev),
+                Ok((_, ev)) => vec![ev],
                 Err(e) if e.raw_os_error() == Some(EAGAIN) => break,
                 Err(e) => panic!("Failed to poll event from fts device: {}", e),
             };
 
-            if let Err(e) = tx.blocking_send(ev) {
-                eprintln!("{}", e);
-                return;
+            for ev in events {
+                if let Err(e) = tx.blocking_send(ev) {
+                    eprintln!("{}", e);
+                    return;
+                }
             }
         }
     }
 }
 
-pub fn read_fts_events() -> anyhow::Result<Receiver<InputEvent>> {
+/// Grabs the `fts` touchscreen, returns its path, capabilities and events stamped by `clock`.
+pub fn read_fts_events(
+    clock: Clock,
+) -> anyhow::Result<(PathBuf, TouchCapabilities, Receiver<InputEvent>)> {
     let (dev_path, _) = enumerate_devices()
         .context("Failed to enumerate devices")?
         .into_iter()
@@ -52,7 +149,7 @@ pub fn read_fts_events() -> anyhow::Result<Receiver<InputEvent>> {
 
     let file = OpenOptions::new()
         .read(true)
-        .open(dev_path)
+        .open(&dev_path)
         .context("Failed to open device")?;
     let fd = file.as_raw_fd();
 
@@ -61,9 +158,13 @@ pub fn read_fts_events() -> anyhow::Result<Receiver<InputEvent>> {
     }
 
     let device = Device::new_from_file(file).context("Failed to create Device from File")?;
+    device
+        .set_clock_id(clock.id().as_raw())
+        .context("Failed to set clock of fts device")?;
+    let capabilities = TouchCapabilities::from_device(&device)?;
     let (tx, rx) = mpsc::channel::<InputEvent>(4);
 
     task::spawn_blocking(move || working_thread(device, tx));
 
-    Ok(rx)
+    Ok((dev_path, capabilities, rx))
 }
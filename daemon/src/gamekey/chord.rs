@@ -0,0 +1,290 @@
+use crate::gamekey::{Event, EventType};
+use evdev_rs::TimeVal;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Output of the [`ChordDetector`].
+#[derive(Debug)]
+pub enum ChordEvent {
+    /// Event of a single trigger, passed on (possibly delayed).
+    Trigger(Event),
+    /// Both triggers went down within the window, or the chord is over.
+    Chord { pressed: bool },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum State {
+    #[default]
+    Idle,
+    /// One trigger is down, its press is held back until the other one follows or the
+    /// window is over.
+    Pending {
+        slot: u32,
+        deadline: Instant,
+        time: TimeVal,
+    },
+    /// The chord is down, holds which triggers are still pressed.
+    Chord { held: [bool; 2] },
+}
+
+/// Tells a press of both triggers at once apart from presses of the single triggers.
+///
+/// Only the upper (0) and lower (1) trigger take part, events of other slots pass through.
+#[derive(Default)]
+pub struct ChordDetector {
+    state: State,
+    /// Triggers whose press was passed on and not released yet.
+    forwarded: [bool; 2],
+}
+
+impl ChordDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn forward(&mut self, ev: Event, out: &mut Vec<ChordEvent>) {
+        if let Some(forwarded) = self.forwarded.get_mut(ev.slot as usize) {
+            *forwarded = ev.r#type == EventType::Press;
+        }
+
+        out.push(ChordEvent::Trigger(ev));
+    }
+
+    /// `window` is `None` while no chord is bound, events are passed through then.
+    pub fn on_event(
+        &mut self,
+        ev: Event,
+        window: Option<Duration>,
+        now: Instant,
+    ) -> Vec<ChordEvent> {
+        let mut out = Vec::new();
+
+        if ev.slot > 1 || !matches!(ev.r#type, EventType::Press | EventType::Release) {
+            out.push(ChordEvent::Trigger(ev));
+            return out;
+        }
+
+        let slot = ev.slot as usize;
+
+        match (&ev.r#type, self.state) {
+            (EventType::Press, State::Idle) => match window {
+                Some(window) if !self.forwarded[slot ^ 1] && !self.forwarded[slot] => {
+                    self.state = State::Pending {
+                        slot: ev.slot,
+                        deadline: now + window,
+                        time: ev.time,
+                    };
+                }
+                // no chord bound, or a trigger is already in use on its own
+                _ => self.forward(ev, &mut out),
+            },
+            (EventType::Press, State::Pending { slot: pending, .. }) if pending != ev.slot => {
+                self.state = State::Chord { held: [true; 2] };
+                out.push(ChordEvent::Chord { pressed: true });
+            }
+            // pressed again through another key of the same trigger, still held back
+            (EventType::Press, State::Pending { .. }) => {}
+            (
+                EventType::Release,
+                State::Pending {
+                    slot: pending,
+                    time,
+                    ..
+                },
+            ) if pending == ev.slot => {
+                // released before the window is over, still a press of its own
+                self.state = State::Idle;
+                self.forward(
+                    Event {
+                        r#type: EventType::Press,
+                        slot: ev.slot,
+                        time,
+                    },
+                    &mut out,
+                );
+                self.forward(ev, &mut out);
+            }
+            (EventType::Release, State::Chord { mut held }) => {
+                if held == [true; 2] {
+                    out.push(ChordEvent::Chord { pressed: false });
+                }
+
+                held[slot] = false;
+                self.state = if held == [false; 2] {
+                    State::Idle
+                } else {
+                    State::Chord { held }
+                };
+            }
+            (EventType::Press, State::Chord { .. }) => {}
+            _ => self.forward(ev, &mut out),
+        }
+
+        out
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) -> Vec<ChordEvent> {
+        let mut out = Vec::new();
+
+        if let State::Pending {
+            slot,
+            deadline,
+            time,
+        } = self.state
+        {
+            if deadline <= now {
+                self.state = State::Idle;
+                self.forward(
+                    Event {
+                        r#type: EventType::Press,
+                        slot,
+                        time,
+                    },
+                    &mut out,
+                );
+            }
+        }
+
+        out
+    }
+
+    /// Returns when [`ChordDetector::on_timeout`] has to be called next.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Pending { deadline, .. } => Some(deadline),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    const WINDOW: Duration = Duration::from_millis(60);
+
+    fn event(r#type: EventType, slot: u32) -> Event {
+        Event {
+            r#type,
+            slot,
+            time: TimeVal::new(0, 0),
+        }
+    }
+
+    /// Short form of the output, `Some(slot)` for triggers and `None` for the chord.
+    fn summary(events: Vec<ChordEvent>) -> Vec<(Option<u32>, bool)> {
+        events
+            .into_iter()
+            .map(|ev| match ev {
+                ChordEvent::Trigger(ev) => (Some(ev.slot), ev.r#type == EventType::Press),
+                ChordEvent::Chord { pressed } => (None, pressed),
+            })
+            .collect()
+    }
+
+    fn send(
+        detector: &mut ChordDetector,
+        r#type: EventType,
+        slot: u32,
+    ) -> Vec<(Option<u32>, bool)> {
+        summary(detector.on_event(event(r#type, slot), Some(WINDOW), Instant::now()))
+    }
+
+    /// Lets `duration` pass, firing the deadline on the way.
+    async fn wait(detector: &mut ChordDetector, duration: Duration) -> Vec<(Option<u32>, bool)> {
+        let end = Instant::now() + duration;
+        let mut out = Vec::new();
+
+        if let Some(deadline) = detector.next_deadline().filter(|d| *d <= end) {
+            advance(deadline.saturating_duration_since(Instant::now())).await;
+            out.extend(summary(detector.on_timeout(Instant::now())));
+        }
+
+        advance(end.saturating_duration_since(Instant::now())).await;
+
+        out
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn detects_chord_within_window() {
+        let mut detector = ChordDetector::new();
+
+        assert!(send(&mut detector, EventType::Press, 0).is_empty());
+        assert!(wait(&mut detector, WINDOW - Duration::from_millis(1))
+            .await
+            .is_empty());
+        assert_eq!(send(&mut detector, EventType::Press, 1), vec![(None, true)]);
+
+        // the first release ends the chord, the second one is swallowed
+        assert_eq!(
+            send(&mut detector, EventType::Release, 1),
+            vec![(None, false)]
+        );
+        assert!(send(&mut detector, EventType::Release, 0).is_empty());
+        assert_eq!(detector.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forwards_press_after_window() {
+        let mut detector = ChordDetector::new();
+
+        assert!(send(&mut detector, EventType::Press, 0).is_empty());
+        assert_eq!(wait(&mut detector, WINDOW).await, vec![(Some(0), true)]);
+
+        // too late for a chord, the other trigger is on its own
+        assert_eq!(
+            send(&mut detector, EventType::Press, 1),
+            vec![(Some(1), true)]
+        );
+        assert_eq!(
+            send(&mut detector, EventType::Release, 0),
+            vec![(Some(0), false)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forwards_early_release() {
+        let mut detector = ChordDetector::new();
+
+        assert!(send(&mut detector, EventType::Press, 1).is_empty());
+        assert!(wait(&mut detector, Duration::from_millis(10))
+            .await
+            .is_empty());
+        assert_eq!(
+            send(&mut detector, EventType::Release, 1),
+            vec![(Some(1), true), (Some(1), false)]
+        );
+        assert!(wait(&mut detector, WINDOW).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passes_through_while_trigger_held() {
+        let mut detector = ChordDetector::new();
+
+        send(&mut detector, EventType::Press, 0);
+        wait(&mut detector, WINDOW).await;
+
+        // the upper trigger is held on its own, no chord can start
+        assert_eq!(
+            send(&mut detector, EventType::Press, 1),
+            vec![(Some(1), true)]
+        );
+        assert_eq!(detector.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_repeated_press() {
+        let mut detector = ChordDetector::new();
+
+        assert!(send(&mut detector, EventType::Press, 0).is_empty());
+        assert!(send(&mut detector, EventType::Press, 0).is_empty());
+
+        // a single press comes out of the window
+        assert_eq!(wait(&mut detector, WINDOW).await, vec![(Some(0), true)]);
+        assert_eq!(
+            send(&mut detector, EventType::Release, 0),
+            vec![(Some(0), false)]
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::gamekey::{Event, EventType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long a classified tap keeps its touch down.
+const TAP_DURATION: Duration = Duration::from_millis(40);
+
+const DEFAULT_LONG_PRESS: Duration = Duration::from_millis(400);
+const DEFAULT_DOUBLE_PRESS: Duration = Duration::from_millis(250);
+const DEFAULT_CHORD: Duration = Duration::from_millis(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Gesture {
+    /// Plain press, also used for short taps once they are told apart from other gestures.
+    Press,
+    LongPress,
+    DoublePress,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GestureEvent {
+    pub slot: u32,
+    pub gesture: Gesture,
+    pub pressed: bool,
+}
+
+/// Thresholds shared by all trigger slots.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GestureTiming {
+    pub long_press: Duration,
+    pub double_press: Duration,
+    /// Time in which both triggers have to be pressed to make a chord.
+    pub chord: Duration,
+}
+
+/// Gestures bound on a slot, only those are waited for before acting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GestureConfig {
+    /// Hold time after which a press becomes a long press.
+    pub long_press: Option<Duration>,
+    /// Time after a short press in which a second press makes a double press.
+    pub double_press: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Pressed, but not classified yet.
+    Pressed {
+        deadline: Option<Instant>,
+    },
+    Held(Gesture),
+    /// Released after a short press, a second press may follow.
+    WaitingSecond {
+        deadline: Instant,
+    },
+    /// Classified as a tap, the release is not sent yet.
+    Tapping {
+        until: Instant,
+    },
+}
+
+/// Turns press/release events of the triggers into [`GestureEvent`]s.
+///
+/// Slots without long or double press bindings are passed through without delay.
+#[derive(Default)]
+pub struct GestureRecognizer {
+    states: HashMap<u32, State>,
+}
+
+impl Default for GestureTiming {
+    fn default() -> Self {
+        Self {
+            long_press: DEFAULT_LONG_PRESS,
+            double_press: DEFAULT_DOUBLE_PRESS,
+            chord: DEFAULT_CHORD,
+        }
+    }
+}
+
+impl GestureConfig {
+    fn is_plain(&self) -> bool {
+        self.long_press.is_none() && self.double_press.is_none()
+    }
+}
+
+impl GestureRecognizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn start(&mut self, slot: u32, gesture: Gesture, out: &mut Vec<GestureEvent>) {
+        self.states.insert(slot, State::Held(gesture));
+        out.push(GestureEvent {
+            slot,
+            gesture,
+            pressed: true,
+        });
+    }
+
+    fn tap(&mut self, slot: u32, now: Instant, out: &mut Vec<GestureEvent>) {
+        self.states.insert(
+            slot,
+            State::Tapping {
+                until: now + TAP_DURATION,
+            },
+        );
+        out.push(GestureEvent {
+            slot,
+            gesture: Gesture::Press,
+            pressed: true,
+        });
+    }
+
+    fn end(&mut self, slot: u32, gesture: Gesture, out: &mut Vec<GestureEvent>) {
+        self.states.insert(slot, State::Idle);
+        out.push(GestureEvent {
+            slot,
+            gesture,
+            pressed: false,
+        });
+    }
+
+    pub fn on_event(
+        &mut self,
+        ev: &Event,
+        config: &GestureConfig,
+        now: Instant,
+    ) -> Vec<GestureEvent> {
+        let mut out = Vec::new();
+        let slot = ev.slot;
+        let mut state = *self.states.get(&slot).unwrap_or(&State::Idle);
+
+        if let (EventType::Press, State::Tapping { .. }) = (&ev.r#type, state) {
+            self.end(slot, Gesture::Press, &mut out);
+            state = State::Idle;
+        }
+
+        match (&ev.r#type, state) {
+            (EventType::Press, State::Idle) if config.is_plain() => {
+                self.start(slot, Gesture::Press, &mut out);
+            }
+            (EventType::Press, State::Idle) => {
+                // without a long press binding, holding longer than the double press
+                // window can't become anything but a plain press
+                let deadline = config.long_press.or(config.double_press).map(|d| now + d);
+                self.states.insert(slot, State::Pressed { deadline });
+            }
+            (EventType::Press, State::WaitingSecond { .. }) => {
+                self.start(slot, Gesture::DoublePress, &mut out);
+            }
+            (EventType::Release, State::Held(gesture)) => {
+                self.end(slot, gesture, &mut out);
+            }
+            (EventType::Release, State::Pressed { .. }) => match config.double_press {
+                Some(window) => {
+                    self.states.insert(
+                        slot,
+                        State::WaitingSecond {
+                            deadline: now + window,
+                        },
+                    );
+                }
+                None => self.tap(slot, now, &mut out),
+            },
+            _ => {}
+        }
+
+        out
+    }
+
+    pub fn on_timeout(
+        &mut self,
+        config: impl Fn(u32) -> GestureConfig,
+        now: Instant,
+    ) -> Vec<GestureEvent> {
+        let mut out = Vec::new();
+        let expired: Vec<(u32, State)> = self
+            .states
+            .iter()
+            .filter(|(_, state)| Self::deadline(state).is_some_and(|d| d <= now))
+            .map(|(slot, state)| (*slot, *state))
+            .collect();
+
+        for (slot, state) in expired {
+            match state {
+                State::Pressed { .. } if config(slot).long_press.is_some() => {
+                    self.start(slot, Gesture::LongPress, &mut out);
+                }
+                State::Pressed { .. } => self.start(slot, Gesture::Press, &mut out),
+                State::WaitingSecond { .. } => self.tap(slot, now, &mut out),
+                State::Tapping { .. } => self.end(slot, Gesture::Press, &mut out),
+                State::Idle | State::Held(_) => {}
+            }
+        }
+
+        out
+    }
+
+    fn deadline(state: &State) -> Option<Instant> {
+        match state {
+            State::Pressed { deadline } => *deadline,
+            State::WaitingSecond { deadline } => Some(*deadline),
+            State::Tapping { until } => Some(*until),
+            State::Idle | State::Held(_) => None,
+        }
+    }
+
+    /// Returns when [`GestureRecognizer::on_timeout`] has to be called next.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.states.values().filter_map(Self::deadline).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::TimeVal;
+    use tokio::time::advance;
+
+    const SLOT: u32 = 0;
+
+    const CONFIG: GestureConfig = GestureConfig {
+        long_press: Some(DEFAULT_LONG_PRESS),
+        double_press: Some(DEFAULT_DOUBLE_PRESS),
+    };
+
+    /// Drives a recognizer like the event loop does, on the paused clock.
+    struct Harness {
+        recognizer: GestureRecognizer,
+        config: GestureConfig,
+    }
+
+    fn event(slot: u32, gesture: Gesture, pressed: bool) -> GestureEvent {
+        GestureEvent {
+            slot,
+            gesture,
+            pressed,
+        }
+    }
+
+    impl Harness {
+        fn new(config: GestureConfig) -> Self {
+            Self {
+                recognizer: GestureRecognizer::new(),
+                config,
+            }
+        }
+
+        fn send(&mut self, r#type: EventType) -> Vec<GestureEvent> {
+            let ev = Event {
+                r#type,
+                slot: SLOT,
+                time: TimeVal::new(0, 0),
+            };
+
+            self.recognizer.on_event(&ev, &self.config, Instant::now())
+        }
+
+        fn press(&mut self) -> Vec<GestureEvent> {
+            self.send(EventType::Press)
+        }
+
+        fn release(&mut self) -> Vec<GestureEvent> {
+            self.send(EventType::Release)
+        }
+
+        /// Lets `duration` pass, firing the deadlines on the way.
+        async fn wait(&mut self, duration: Duration) -> Vec<GestureEvent> {
+            let end = Instant::now() + duration;
+            let mut out = Vec::new();
+
+            while let Some(deadline) = self.recognizer.next_deadline().filter(|d| *d <= end) {
+                advance(deadline.saturating_duration_since(Instant::now())).await;
+
+                let config = self.config;
+                out.extend(self.recognizer.on_timeout(|_| config, Instant::now()));
+            }
+
+            advance(end.saturating_duration_since(Instant::now())).await;
+
+            out
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passes_plain_presses_through() {
+        let mut harness = Harness::new(GestureConfig::default());
+
+        assert_eq!(harness.press(), vec![event(SLOT, Gesture::Press, true)]);
+        assert!(harness.wait(Duration::from_secs(5)).await.is_empty());
+        // released while held
+        assert_eq!(harness.release(), vec![event(SLOT, Gesture::Press, false)]);
+        assert_eq!(harness.recognizer.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn taps_after_double_press_window() {
+        let mut harness = Harness::new(CONFIG);
+
+        assert!(harness.press().is_empty());
+        assert!(harness.wait(Duration::from_millis(50)).await.is_empty());
+        assert!(harness.release().is_empty());
+
+        assert!(harness
+            .wait(DEFAULT_DOUBLE_PRESS - Duration::from_millis(1))
+            .await
+            .is_empty());
+        assert_eq!(
+            harness.wait(Duration::from_millis(1)).await,
+            vec![event(SLOT, Gesture::Press, true)]
+        );
+        assert_eq!(
+            harness.wait(TAP_DURATION).await,
+            vec![event(SLOT, Gesture::Press, false)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_presses_at_threshold() {
+        let mut harness = Harness::new(CONFIG);
+
+        assert!(harness.press().is_empty());
+        assert!(harness
+            .wait(DEFAULT_LONG_PRESS - Duration::from_millis(1))
+            .await
+            .is_empty());
+        assert_eq!(
+            harness.wait(Duration::from_millis(1)).await,
+            vec![event(SLOT, Gesture::LongPress, true)]
+        );
+
+        // held until released
+        assert!(harness.wait(Duration::from_secs(5)).await.is_empty());
+        assert_eq!(
+            harness.release(),
+            vec![event(SLOT, Gesture::LongPress, false)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn double_presses_within_window() {
+        let mut harness = Harness::new(CONFIG);
+
+        harness.press();
+        harness.release();
+        assert!(harness
+            .wait(DEFAULT_DOUBLE_PRESS - Duration::from_millis(1))
+            .await
+            .is_empty());
+
+        assert_eq!(
+            harness.press(),
+            vec![event(SLOT, Gesture::DoublePress, true)]
+        );
+        assert!(harness.wait(Duration::from_secs(5)).await.is_empty());
+        assert_eq!(
+            harness.release(),
+            vec![event(SLOT, Gesture::DoublePress, false)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn presses_again_after_window() {
+        let mut harness = Harness::new(CONFIG);
+
+        harness.press();
+        harness.release();
+        assert_eq!(
+            harness.wait(DEFAULT_DOUBLE_PRESS).await,
+            vec![event(SLOT, Gesture::Press, true)]
+        );
+
+        // the tap is cut short, the new press is classified on its own
+        assert_eq!(harness.press(), vec![event(SLOT, Gesture::Press, false)]);
+        assert_eq!(
+            harness.wait(DEFAULT_LONG_PRESS).await,
+            vec![event(SLOT, Gesture::LongPress, true)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn taps_right_away_without_double_press() {
+        let mut harness = Harness::new(GestureConfig {
+            long_press: Some(DEFAULT_LONG_PRESS),
+            double_press: None,
+        });
+
+        harness.press();
+        assert_eq!(harness.release(), vec![event(SLOT, Gesture::Press, true)]);
+        assert_eq!(
+            harness.wait(TAP_DURATION).await,
+            vec![event(SLOT, Gesture::Press, false)]
+        );
+    }
+}
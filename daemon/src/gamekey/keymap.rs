@@ -0,0 +1,318 @@
+use crate::gamekey::{Event, EventType};
+use crate::TRIGGER_COUNT;
+use anyhow::Context;
+use evdev_rs::enums::{EventCode, EV_KEY};
+use evdev_rs::InputEvent;
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::str::FromStr;
+
+#[cfg(not(feature = "local"))]
+pub const KEYMAP_PATH: &str = "/vendor/etc/gamekeyd/keymap.conf";
+#[cfg(feature = "local")]
+pub const KEYMAP_PATH: &str = "keymap.conf";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyBinding {
+    pub code: EV_KEY,
+    pub value: i32,
+    pub r#type: EventType,
+    pub slot: u32,
+}
+
+/// Table translating `EV_KEY` events of the gamekey device into [`Event`]s.
+///
+/// The first binding matching both the key code and the value wins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    bindings: Vec<KeyBinding>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidFieldCount(usize),
+    UnknownKey(usize, String),
+    InvalidValue(usize, String),
+    UnknownEventType(usize, String),
+    InvalidSlot(usize, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidFieldCount(line) => {
+                write!(f, "line {}: expected `<key> <value> <event> <slot>`", line)
+            }
+            Error::UnknownKey(line, key) => write!(f, "line {}: unknown key `{}`", line, key),
+            Error::InvalidValue(line, value) => {
+                write!(f, "line {}: invalid key value `{}`", line, value)
+            }
+            Error::UnknownEventType(line, ty) => {
+                write!(f, "line {}: unknown event type `{}`", line, ty)
+            }
+            Error::InvalidSlot(line, slot) => write!(f, "line {}: invalid slot `{}`", line, slot),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl FromStr for EventType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(EventType::Open),
+            "close" => Ok(EventType::Close),
+            "press" => Ok(EventType::Press),
+            "release" => Ok(EventType::Release),
+            _ => Err(()),
+        }
+    }
+}
+
+impl KeyBinding {
+    pub fn new(code: EV_KEY, value: i32, r#type: EventType, slot: u32) -> Self {
+        Self {
+            code,
+            value,
+            r#type,
+            slot,
+        }
+    }
+}
+
+impl Default for KeyMap {
+    /// Layout of the stock `xm_gamekey` driver.
+    fn default() -> Self {
+        Self::new(vec![
+            KeyBinding::new(EV_KEY::KEY_F1, 1, EventType::Press, 0),
+            KeyBinding::new(EV_KEY::KEY_F1, 0, EventType::Release, 0),
+            KeyBinding::new(EV_KEY::KEY_F2, 1, EventType::Press, 1),
+            KeyBinding::new(EV_KEY::KEY_F2, 0, EventType::Release, 1),
+            KeyBinding::new(EV_KEY::KEY_F3, 1, EventType::Open, 0),
+            KeyBinding::new(EV_KEY::KEY_F4, 1, EventType::Close, 0),
+            KeyBinding::new(EV_KEY::KEY_F5, 1, EventType::Open, 1),
+            KeyBinding::new(EV_KEY::KEY_F6, 1, EventType::Close, 1),
+        ])
+    }
+}
+
+impl KeyMap {
+    pub fn new(bindings: Vec<KeyBinding>) -> Self {
+        Self { bindings }
+    }
+
+    pub fn map(&self, ev: &InputEvent) -> Option<Event> {
+        let EventCode::EV_KEY(key) = ev.event_code else {
+            return None;
+        };
+
+        self.bindings
+            .iter()
+            .find(|b| b.code == key && b.value == ev.value)
+            .map(|b| Event {
+                r#type: b.r#type.clone(),
+                slot: b.slot,
+                time: ev.time,
+            })
+    }
+
+    /// Parses a keymap in the following format:
+    ///
+    /// ```text
+    /// # key     value  event    slot
+    /// KEY_F1    1      press    0
+    /// KEY_F1    0      release  0
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut bindings = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.split('#').next().unwrap_or("").trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [key, value, ty, slot] = fields[..] else {
+                return Err(Error::InvalidFieldCount(line_no));
+            };
+
+            bindings.push(KeyBinding {
+                code: EV_KEY::from_str(key)
+                    .map_err(|_| Error::UnknownKey(line_no, key.to_string()))?,
+                value: value
+                    .parse()
+                    .map_err(|_| Error::InvalidValue(line_no, value.to_string()))?,
+                r#type: EventType::from_str(ty)
+                    .map_err(|_| Error::UnknownEventType(line_no, ty.to_string()))?,
+                // slots past the triggers belong to the chord, only the detector makes those
+                slot: slot
+                    .parse()
+                    .ok()
+                    .filter(|slot| (*slot as usize) < TRIGGER_COUNT)
+                    .ok_or_else(|| Error::InvalidSlot(line_no, slot.to_string()))?,
+            });
+        }
+
+        Ok(Self::new(bindings))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read keymap {}", path.as_ref().display()))?;
+
+        Self::parse(&text).context("Failed to parse keymap")
+    }
+
+    /// Loads the keymap from [`KEYMAP_PATH`], falling back to the stock layout.
+    pub fn load_or_default() -> Self {
+        match Self::load(KEYMAP_PATH) {
+            Ok(keymap) => keymap,
+            Err(e) => {
+                log::warn!("Using default keymap: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::TimeVal;
+
+    fn key_event(key: EV_KEY, value: i32) -> InputEvent {
+        InputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_KEY(key), value)
+    }
+
+    fn mapped(keymap: &KeyMap, key: EV_KEY, value: i32) -> Option<(EventType, u32)> {
+        keymap
+            .map(&key_event(key, value))
+            .map(|ev| (ev.r#type, ev.slot))
+    }
+
+    #[test]
+    fn default_matches_xm_gamekey() {
+        let keymap = KeyMap::default();
+
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F1, 1),
+            Some((EventType::Press, 0))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F1, 0),
+            Some((EventType::Release, 0))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F2, 1),
+            Some((EventType::Press, 1))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F2, 0),
+            Some((EventType::Release, 1))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F3, 1),
+            Some((EventType::Open, 0))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F4, 1),
+            Some((EventType::Close, 0))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F5, 1),
+            Some((EventType::Open, 1))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F6, 1),
+            Some((EventType::Close, 1))
+        );
+
+        assert_eq!(mapped(&keymap, EV_KEY::KEY_F3, 0), None);
+        assert_eq!(mapped(&keymap, EV_KEY::KEY_F1, 2), None);
+        assert_eq!(mapped(&keymap, EV_KEY::KEY_F7, 1), None);
+    }
+
+    #[test]
+    fn ignores_non_key_events() {
+        let keymap = KeyMap::default();
+        let ev = InputEvent::new(
+            &TimeVal::new(0, 0),
+            &EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT),
+            0,
+        );
+
+        assert!(keymap.map(&ev).is_none());
+    }
+
+    #[test]
+    fn parses_custom_table() {
+        let keymap = KeyMap::parse(
+            "# custom board\n\
+             BTN_TRIGGER_HAPPY1 1 press 0 # upper\n\
+             BTN_TRIGGER_HAPPY1 0 release 0\n\
+             \n\
+             KEY_VOLUMEUP 1 press 1\n",
+        )
+        .unwrap();
+
+        assert_eq!(keymap.bindings.len(), 3);
+        assert_eq!(
+            mapped(&keymap, EV_KEY::BTN_TRIGGER_HAPPY1, 1),
+            Some((EventType::Press, 0))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::BTN_TRIGGER_HAPPY1, 0),
+            Some((EventType::Release, 0))
+        );
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_VOLUMEUP, 1),
+            Some((EventType::Press, 1))
+        );
+        assert_eq!(mapped(&keymap, EV_KEY::KEY_F1, 1), None);
+    }
+
+    #[test]
+    fn first_binding_wins() {
+        let keymap = KeyMap::parse("KEY_F1 1 open 0\nKEY_F1 1 press 1\n").unwrap();
+
+        assert_eq!(
+            mapped(&keymap, EV_KEY::KEY_F1, 1),
+            Some((EventType::Open, 0))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(matches!(
+            KeyMap::parse("KEY_F1 1 press"),
+            Err(Error::InvalidFieldCount(1))
+        ));
+        assert!(matches!(
+            KeyMap::parse("\nKEY_NOPE 1 press 0"),
+            Err(Error::UnknownKey(2, _))
+        ));
+        assert!(matches!(
+            KeyMap::parse("KEY_F1 x press 0"),
+            Err(Error::InvalidValue(1, _))
+        ));
+        assert!(matches!(
+            KeyMap::parse("KEY_F1 1 squeeze 0"),
+            Err(Error::UnknownEventType(1, _))
+        ));
+        assert!(matches!(
+            KeyMap::parse("KEY_F1 1 press -1"),
+            Err(Error::InvalidSlot(1, _))
+        ));
+        // the chord's slot
+        assert!(matches!(
+            KeyMap::parse("KEY_F1 1 press 2"),
+            Err(Error::InvalidSlot(1, _))
+        ));
+    }
+}
@@ -1,16 +1,24 @@
 use anyhow::Context;
-use evdev_rs::enums::{EventCode, EV_KEY};
-use evdev_rs::{Device, InputEvent, ReadFlag};
+use evdev_rs::{Device, ReadFlag, TimeVal};
 use nix::errno::Errno;
 use nix::libc::EAGAIN;
 use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use serde::{Deserialize, Serialize};
 use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task;
 use crate::utils::udev::enumerate_devices;
+use keymap::KeyMap;
 
-#[derive(Debug)]
+pub mod chord;
+pub mod gesture;
+pub mod keymap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub enum EventType {
     Open,
     Close,
@@ -18,81 +26,15 @@ pub enum EventType {
     Release,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Event {
     pub r#type: EventType,
     pub slot: u32,
+    /// Timestamp of the key event as set by the kernel.
+    pub time: TimeVal,
 }
 
-fn map_event(ev: InputEvent) -> Option<Event> {
-    match ev.event_code {
-        EventCode::EV_KEY(key) => match key {
-            EV_KEY::KEY_F1 => Some(Event {
-                slot: 0,
-                r#type: if ev.value == 1 {
-                    EventType::Press
-                } else {
-                    EventType::Release
-                },
-            }),
-            EV_KEY::KEY_F2 => Some(Event {
-                slot: 1,
-                r#type: if ev.value == 1 {
-                    EventType::Press
-                } else {
-                    EventType::Release
-                },
-            }),
-
-            EV_KEY::KEY_F3 => {
-                if ev.value == 1 {
-                    Some(Event {
-                        slot: 0,
-                        r#type: EventType::Open,
-                    })
-                } else {
-                    None
-                }
-            }
-            EV_KEY::KEY_F4 => {
-                if ev.value == 1 {
-                    Some(Event {
-                        slot: 0,
-                        r#type: EventType::Close,
-                    })
-                } else {
-                    None
-                }
-            }
-
-            EV_KEY::KEY_F5 => {
-                if ev.value == 1 {
-                    Some(Event {
-                        slot: 1,
-                        r#type: EventType::Open,
-                    })
-                } else {
-                    None
-                }
-            }
-            EV_KEY::KEY_F6 => {
-                if ev.value == 1 {
-                    Some(Event {
-                        slot: 1,
-                        r#type: EventType::Close,
-                    })
-                } else {
-                    None
-                }
-            }
-
-            _ => None,
-        },
-        _ => None,
-    }
-}
-
-fn working_thread(device: Device, tx: Sender<Event>) {
+fn working_thread(device: Device, keymap: Arc<RwLock<KeyMap>>, tx: Sender<Event>) {
     let fd = device.file().as_fd();
     let mut pfd = [PollFd::new(fd, PollFlags::POLLIN)];
 
@@ -106,7 +48,7 @@ fn working_thread(device: Device, tx: Sender<Event>) {
 
         loop {
             let ev = match device.next_event(ReadFlag::BLOCKING) {
-                Ok((_, ev)) => map_event(ev),
+                Ok((_, ev)) => keymap.read().unwrap().map(&ev),
                 Err(e) if e.raw_os_error() == Some(EAGAIN) => break,
                 Err(e) => panic!("Failed to poll event from gamekey device: {}", e),
             };
@@ -122,7 +64,9 @@ fn working_thread(device: Device, tx: Sender<Event>) {
     }
 }
 
-pub fn read_gamekey_events() -> anyhow::Result<Receiver<Event>> {
+pub fn read_gamekey_events(
+    keymap: Arc<RwLock<KeyMap>>,
+) -> anyhow::Result<(PathBuf, Receiver<Event>)> {
     let (dev_path, _) = enumerate_devices()
         .context("Failed to enumerate devices")?
         .into_iter()
@@ -132,10 +76,10 @@ pub fn read_gamekey_events() -> anyhow::Result<Receiver<Event>> {
             "Input device with name `xm_gamekey` not found",
         ))?;
 
-    let device = Device::new_from_path(dev_path).context("Failed to create Device from path")?;
+    let device = Device::new_from_path(&dev_path).context("Failed to create Device from path")?;
     let (tx, rx) = mpsc::channel::<Event>(4);
 
-    task::spawn_blocking(move || working_thread(device, tx));
+    task::spawn_blocking(move || working_thread(device, keymap, tx));
 
-    Ok(rx)
+    Ok((dev_path, rx))
 }
@@ -0,0 +1,90 @@
+use crate::gamekey::{Event, EventType};
+use crate::TRIGGER_COUNT;
+use anyhow::{bail, Context};
+
+/// Makes an event as if trigger `slot` did `r#type` just now.
+pub fn synthetic_event(r#type: EventType, slot: u32) -> anyhow::Result<Event> {
+    if slot as usize >= TRIGGER_COUNT {
+        bail!("Invalid slot {}", slot);
+    }
+
+    Ok(Event {
+        r#type,
+        slot,
+        time: std::time::SystemTime::now().try_into()?,
+    })
+}
+
+/// Parses `<press|release|open|close> <slot>`.
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
+pub fn parse_command(line: &str) -> anyhow::Result<Event> {
+    let mut words = line.split_whitespace();
+
+    let r#type = match words.next() {
+        Some("press") => EventType::Press,
+        Some("release") => EventType::Release,
+        Some("open") => EventType::Open,
+        Some("close") => EventType::Close,
+        Some(word) => bail!("Unknown event `{}`", word),
+        None => bail!("Missing event"),
+    };
+    let slot = words
+        .next()
+        .context("Missing slot")?
+        .parse()
+        .context("Invalid slot")?;
+
+    if words.next().is_some() {
+        bail!("Trailing input");
+    }
+
+    synthetic_event(r#type, slot)
+}
+
+/// Injects the events typed on stdin, one command of [`parse_command`] per line.
+#[cfg(feature = "local")]
+pub async fn read_stdin(inject: tokio::sync::mpsc::Sender<Event>) -> anyhow::Result<()> {
+    use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+    let mut lines = BufReader::new(stdin()).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_command(&line) {
+            Ok(ev) => inject.send(ev).await?,
+            Err(e) => log::warn!("Ignoring `{}`: {:#}", line, e),
+        }
+    }
+
+    // running detached closes stdin right away, that's no reason to stop
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let ev = parse_command("press 1").unwrap();
+        assert_eq!(ev.r#type, EventType::Press);
+        assert_eq!(ev.slot, 1);
+
+        let ev = parse_command("  close   0 ").unwrap();
+        assert_eq!(ev.r#type, EventType::Close);
+        assert_eq!(ev.slot, 0);
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert!(parse_command("").is_err());
+        assert!(parse_command("squeeze 0").is_err());
+        assert!(parse_command("press").is_err());
+        assert!(parse_command("press -1").is_err());
+        assert!(parse_command("press 2").is_err());
+        assert!(parse_command("press 0 now").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::gamekey::{Event, EventType};
+use evdev_rs::TimeVal;
+use gamekeyd_aidl::{
+    aidl::org::ingres::gamekeys::{
+        GameKeyEvent::GameKeyEvent, GameKeyEventKind::GameKeyEventKind,
+        IGameKeyListener::IGameKeyListener,
+    },
+    binder::{DeathRecipient, SpIBinder, StatusCode, Strong},
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+struct Registration {
+    listener: Strong<dyn IGameKeyListener>,
+    binder: SpIBinder,
+    death: DeathRecipient,
+}
+
+/// Clients registered for gamekey events, dropped when their process dies.
+#[derive(Default)]
+pub struct ListenerRegistry {
+    registrations: Arc<Mutex<Vec<Registration>>>,
+}
+
+fn to_nanos(time: TimeVal) -> i64 {
+    let secs = u64::try_from(time.tv_sec).unwrap_or(0);
+    let micros = u64::try_from(time.tv_usec).unwrap_or(0);
+
+    (Duration::from_secs(secs) + Duration::from_micros(micros)).as_nanos() as i64
+}
+
+fn to_aidl_event(ev: &Event) -> GameKeyEvent {
+    GameKeyEvent {
+        kind: match ev.r#type {
+            EventType::Open => GameKeyEventKind::OPEN,
+            EventType::Close => GameKeyEventKind::CLOSE,
+            EventType::Press => GameKeyEventKind::PRESS,
+            EventType::Release => GameKeyEventKind::RELEASE,
+        },
+        slot: ev.slot as i32,
+        timestampNs: to_nanos(ev.time),
+    }
+}
+
+impl ListenerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, listener: &Strong<dyn IGameKeyListener>) -> Result<(), StatusCode> {
+        let mut binder = listener.as_binder();
+        let mut registrations = self.registrations.lock().unwrap();
+
+        if registrations.iter().any(|r| r.binder == binder) {
+            return Ok(());
+        }
+
+        let weak = Arc::downgrade(&self.registrations);
+        let dead = binder.clone();
+        let mut death = DeathRecipient::new(move || {
+            if let Some(registrations) = weak.upgrade() {
+                registrations.lock().unwrap().retain(|r| r.binder != dead);
+                log::info!("Listener died, unregistered");
+            }
+        });
+        binder.link_to_death(&mut death)?;
+
+        registrations.push(Registration {
+            listener: listener.clone(),
+            binder,
+            death,
+        });
+
+        Ok(())
+    }
+
+    pub fn unregister(&self, listener: &Strong<dyn IGameKeyListener>) {
+        let binder = listener.as_binder();
+        let mut registrations = self.registrations.lock().unwrap();
+
+        if let Some(i) = registrations.iter().position(|r| r.binder == binder) {
+            let mut registration = registrations.remove(i);
+            // fails if the client is already dead, it's gone either way
+            let _ = registration.binder.unlink_to_death(&mut registration.death);
+        }
+    }
+
+    pub fn notify(&self, ev: &Event) {
+        let listeners: Vec<Strong<dyn IGameKeyListener>> = self
+            .registrations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.listener.clone())
+            .collect();
+        let ev = to_aidl_event(ev);
+
+        for listener in listeners {
+            if let Err(e) = listener.onGameKeyEvent(&ev) {
+                log::warn!("Failed to notify listener: {}", e);
+            }
+        }
+    }
+}
+
+/// Passes the events of the gamekey event loop on to the registered listeners.
+pub async fn forward_events(registry: Arc<ListenerRegistry>, mut rx: broadcast::Receiver<Event>) {
+    loop {
+        match rx.recv().await {
+            Ok(ev) => registry.notify(&ev),
+            Err(RecvError::Lagged(count)) => {
+                log::warn!("Listeners missed {} gamekey events", count)
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,504 @@
+use crate::action::Signal;
+use crate::touch_emulator::TouchEmulator;
+use futures::future::{join_all, BoxFuture};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::Formatter;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::time::sleep;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MacroStep {
+    Down {
+        finger: usize,
+        x: i32,
+        y: i32,
+    },
+    Move {
+        finger: usize,
+        x: i32,
+        y: i32,
+    },
+    Up {
+        finger: usize,
+    },
+    Wait(Duration),
+    /// Runs every branch concurrently and continues once all of them are done.
+    Parallel(Vec<Vec<MacroStep>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ReleasePolicy {
+    /// Stop at the next step and lift every finger once the trigger is released.
+    Cancel,
+    /// Run the macro to the end regardless of the trigger.
+    Finish,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Macro {
+    pub steps: Vec<MacroStep>,
+    pub on_release: ReleasePolicy,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidFinger(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidFinger(finger) => write!(f, "Invalid finger {}!", finger),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ReleasePolicy {
+    /// Whether a macro following the policy stops on `signal`.
+    fn stops_on(&self, signal: Signal) -> bool {
+        match signal {
+            Signal::Held => false,
+            Signal::Released => *self == ReleasePolicy::Cancel,
+            Signal::Cancelled => true,
+        }
+    }
+}
+
+impl Macro {
+    /// Checks that every step only touches fingers below `finger_count`.
+    pub fn validate(&self, finger_count: usize) -> Result<(), Error> {
+        fn check(steps: &[MacroStep], finger_count: usize) -> Result<(), Error> {
+            for step in steps {
+                match step {
+                    MacroStep::Down { finger, .. }
+                    | MacroStep::Move { finger, .. }
+                    | MacroStep::Up { finger }
+                        if *finger >= finger_count =>
+                    {
+                        return Err(Error::InvalidFinger(*finger));
+                    }
+                    MacroStep::Parallel(branches) => {
+                        for branch in branches {
+                            check(branch, finger_count)?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            Ok(())
+        }
+
+        check(&self.steps, finger_count)
+    }
+}
+
+/// Plays [`Macro`]s on a range of [`TouchEmulator`] slots, one slot per finger.
+pub struct MacroRunner<'a> {
+    emulator: &'a Mutex<TouchEmulator>,
+    first_slot: usize,
+    finger_count: usize,
+}
+
+impl<'a> MacroRunner<'a> {
+    pub fn new(emulator: &'a Mutex<TouchEmulator>, first_slot: usize, finger_count: usize) -> Self {
+        Self {
+            emulator,
+            first_slot,
+            finger_count,
+        }
+    }
+
+    fn slot(&self, finger: usize) -> anyhow::Result<usize> {
+        if finger >= self.finger_count {
+            return Err(Error::InvalidFinger(finger).into());
+        }
+
+        Ok(self.first_slot + finger)
+    }
+
+    /// Returns `false` once the macro has to stop because of `signal`.
+    async fn wait(
+        &self,
+        duration: Duration,
+        policy: ReleasePolicy,
+        signal: &mut watch::Receiver<Signal>,
+    ) -> bool {
+        let stopped = async {
+            while !policy.stops_on(*signal.borrow_and_update()) {
+                if signal.changed().await.is_err() {
+                    // nobody can stop us anymore
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = sleep(duration) => true,
+            _ = stopped => false,
+        }
+    }
+
+    fn run_steps<'s>(
+        &'s self,
+        steps: &'s [MacroStep],
+        policy: ReleasePolicy,
+        mut signal: watch::Receiver<Signal>,
+    ) -> BoxFuture<'s, anyhow::Result<bool>> {
+        async move {
+            for step in steps {
+                if policy.stops_on(*signal.borrow_and_update()) {
+                    return Ok(false);
+                }
+
+                match step {
+                    MacroStep::Down { finger, x, y } => {
+                        let slot = self.slot(*finger)?;
+                        self.emulator.lock().await.start_tap(slot, *x, *y).await?;
+                    }
+                    MacroStep::Move { finger, x, y } => {
+                        let slot = self.slot(*finger)?;
+                        self.emulator.lock().await.move_tap(slot, *x, *y).await?;
+                    }
+                    MacroStep::Up { finger } => {
+                        let slot = self.slot(*finger)?;
+                        self.emulator.lock().await.stop_tap(slot).await?;
+                    }
+                    MacroStep::Wait(duration) => {
+                        if !self.wait(*duration, policy, &mut signal).await {
+                            return Ok(false);
+                        }
+                    }
+                    MacroStep::Parallel(branches) => {
+                        let results = join_all(
+                            branches
+                                .iter()
+                                .map(|branch| self.run_steps(branch, policy, signal.clone())),
+                        )
+                        .await;
+
+                        let mut finished = true;
+                        for res in results {
+                            finished &= res?;
+                        }
+
+                        if !finished {
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    /// Lifts every finger that is still down.
+    pub async fn lift_all(&self) -> anyhow::Result<()> {
+        let mut emulator = self.emulator.lock().await;
+
+        for slot in self.first_slot..self.first_slot + self.finger_count {
+            emulator.stop_tap(slot).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Plays `macro_` until it ends, until `signal` is cancelled or, for
+    /// [`ReleasePolicy::Cancel`], until it is released.
+    ///
+    /// Fingers left down by the macro are lifted afterwards. Returns whether the macro
+    /// ran to the end.
+    pub async fn run(
+        &self,
+        macro_: &Macro,
+        signal: watch::Receiver<Signal>,
+    ) -> anyhow::Result<bool> {
+        let res = self
+            .run_steps(&macro_.steps, macro_.on_release, signal)
+            .await;
+        let lifted = self.lift_all().await;
+
+        let finished = res?;
+        lifted?;
+
+        Ok(finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Rotation;
+    use crate::utils::clock::Clock;
+    use evdev_rs::enums::{EventCode, EV_ABS, EV_KEY, EV_SYN};
+    use evdev_rs::InputEvent;
+    use tokio::sync::mpsc::Receiver;
+
+    const SLOT: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT);
+    const TRACKING_ID: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID);
+    const TOUCH: EventCode = EventCode::EV_KEY(EV_KEY::BTN_TOUCH);
+    const FINGER: EventCode = EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER);
+    const X: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X);
+    const Y: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y);
+    const SYN: EventCode = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+
+    async fn collect(mut rx: Receiver<InputEvent>) -> Vec<(EventCode, i32)> {
+        let mut events = Vec::new();
+
+        while let Some(ev) = rx.recv().await {
+            events.push((ev.event_code, ev.value));
+        }
+
+        events
+    }
+
+    /// Runs `macro_` on fingers starting at slot 1 of a fresh emulator.
+    ///
+    /// `release_after` releases the trigger after the given time.
+    async fn play(
+        macro_: Macro,
+        release_after: Option<Duration>,
+    ) -> (anyhow::Result<bool>, Vec<(EventCode, i32)>) {
+        let (mut emulator, rx) = TouchEmulator::new(4, Clock::default()).unwrap();
+        // a display as large as the panel, so points come out as given
+        emulator
+            .display_mut()
+            .set_display(10800, 24000, Rotation::Rotation0)
+            .unwrap();
+        let events = tokio::spawn(collect(rx));
+        let (signal, signal_rx) = watch::channel(Signal::Held);
+
+        if let Some(after) = release_after {
+            tokio::spawn(async move {
+                sleep(after).await;
+                let _ = signal.send(Signal::Released);
+            });
+        }
+
+        let res = {
+            let emulator = Mutex::new(emulator);
+            let res = MacroRunner::new(&emulator, 1, 3)
+                .run(&macro_, signal_rx)
+                .await;
+            drop(emulator);
+            res
+        };
+
+        (res, events.await.unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_steps_in_order() {
+        let (res, events) = play(
+            Macro {
+                steps: vec![
+                    MacroStep::Down {
+                        finger: 0,
+                        x: 10,
+                        y: 20,
+                    },
+                    MacroStep::Wait(Duration::from_millis(5)),
+                    MacroStep::Move {
+                        finger: 0,
+                        x: 30,
+                        y: 40,
+                    },
+                    MacroStep::Up { finger: 0 },
+                ],
+                on_release: ReleasePolicy::Cancel,
+            },
+            None,
+        )
+        .await;
+
+        assert!(res.unwrap());
+        assert_eq!(
+            events,
+            vec![
+                (SLOT, 1),
+                (TRACKING_ID, 0),
+                (TOUCH, 1),
+                (FINGER, 1),
+                (X, 10),
+                (Y, 20),
+                (SYN, 0),
+                (SLOT, 1),
+                (X, 30),
+                (Y, 40),
+                (SYN, 0),
+                (SLOT, 1),
+                (TRACKING_ID, -1),
+                (TOUCH, 0),
+                (FINGER, 0),
+                (SYN, 0),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_parallel_branches() {
+        let (res, events) = play(
+            Macro {
+                steps: vec![MacroStep::Parallel(vec![
+                    vec![
+                        MacroStep::Down {
+                            finger: 0,
+                            x: 1,
+                            y: 1,
+                        },
+                        MacroStep::Wait(Duration::from_millis(60)),
+                        MacroStep::Up { finger: 0 },
+                    ],
+                    vec![
+                        MacroStep::Wait(Duration::from_millis(20)),
+                        MacroStep::Down {
+                            finger: 2,
+                            x: 2,
+                            y: 2,
+                        },
+                    ],
+                ])],
+                on_release: ReleasePolicy::Finish,
+            },
+            None,
+        )
+        .await;
+
+        assert!(res.unwrap());
+
+        let frames: Vec<_> = events
+            .iter()
+            .filter(|(code, _)| *code == SLOT || *code == TRACKING_ID)
+            .copied()
+            .collect();
+
+        assert_eq!(
+            frames,
+            vec![
+                // finger 0 down
+                (SLOT, 1),
+                (TRACKING_ID, 0),
+                // finger 2 down after 20ms
+                (SLOT, 3),
+                (TRACKING_ID, 1),
+                // finger 0 up after 60ms
+                (SLOT, 1),
+                (TRACKING_ID, -1),
+                // finger 2 is left down by the macro and lifted at the end
+                (SLOT, 3),
+                (TRACKING_ID, -1),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn release_cancels_and_lifts_fingers() {
+        let (res, events) = play(
+            Macro {
+                steps: vec![
+                    MacroStep::Down {
+                        finger: 1,
+                        x: 5,
+                        y: 5,
+                    },
+                    MacroStep::Wait(Duration::from_secs(10)),
+                    MacroStep::Move {
+                        finger: 1,
+                        x: 6,
+                        y: 6,
+                    },
+                ],
+                on_release: ReleasePolicy::Cancel,
+            },
+            Some(Duration::from_millis(10)),
+        )
+        .await;
+
+        assert!(!res.unwrap());
+        assert!(!events.contains(&(X, 6)));
+        assert_eq!(
+            events[events.len() - 5..],
+            [
+                (SLOT, 2),
+                (TRACKING_ID, -1),
+                (TOUCH, 0),
+                (FINGER, 0),
+                (SYN, 0)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finish_policy_ignores_release() {
+        let (res, events) = play(
+            Macro {
+                steps: vec![
+                    MacroStep::Down {
+                        finger: 0,
+                        x: 5,
+                        y: 5,
+                    },
+                    MacroStep::Wait(Duration::from_millis(30)),
+                    MacroStep::Move {
+                        finger: 0,
+                        x: 6,
+                        y: 6,
+                    },
+                    MacroStep::Up { finger: 0 },
+                ],
+                on_release: ReleasePolicy::Finish,
+            },
+            Some(Duration::from_millis(5)),
+        )
+        .await;
+
+        assert!(res.unwrap());
+        assert!(events.contains(&(X, 6)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_step_lifts_fingers() {
+        let (res, events) = play(
+            Macro {
+                steps: vec![
+                    MacroStep::Down {
+                        finger: 0,
+                        x: 5,
+                        y: 5,
+                    },
+                    // finger 1 was never put down
+                    MacroStep::Move {
+                        finger: 1,
+                        x: 6,
+                        y: 6,
+                    },
+                ],
+                on_release: ReleasePolicy::Cancel,
+            },
+            None,
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(events.last(), Some(&(SYN, 0)));
+        assert!(events.contains(&(TRACKING_ID, -1)));
+    }
+
+    #[test]
+    fn validates_fingers() {
+        let macro_ = Macro {
+            steps: vec![MacroStep::Parallel(vec![vec![MacroStep::Up { finger: 3 }]])],
+            on_release: ReleasePolicy::Cancel,
+        };
+
+        assert!(macro_.validate(4).is_ok());
+        assert!(matches!(macro_.validate(3), Err(Error::InvalidFinger(3))));
+    }
+}
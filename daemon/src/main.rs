@@ -1,22 +1,53 @@
+use crate::action::{Action, ActionRunner, TurboMode, FINGERS_PER_SLOT};
+use crate::gamekey::chord::{ChordDetector, ChordEvent};
+use crate::gamekey::gesture::{
+    Gesture, GestureConfig, GestureEvent, GestureRecognizer, GestureTiming,
+};
+use crate::gamekey::keymap::{KeyMap, KEYMAP_PATH};
 use crate::gamekey::read_gamekey_events;
+use crate::settings::SettingsController;
+use crate::status::{DaemonStatus, SharedStatus, TriggerState};
+use crate::storage::{data_dir, SettingsStorage};
 use anyhow::Context;
-use gamekey::EventType;
+use evdev_rs::enums::EV_ABS;
+use gamekey::{Event, EventType};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::runtime::Runtime;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, mpsc, watch, Mutex, RwLock};
+use tokio::time::{sleep_until, Instant};
 use touch_emulator::TouchEmulator;
 
 use crate::fts::read_fts_events;
-use crate::touch_merger::{TouchMerger, TouchSourceDeclaration};
+use crate::suppression::SuppressionConfig;
+use crate::touch_merger::{SourceKind, TouchMerger, TouchSourceDeclaration};
+use crate::utils::clock::Clock;
+use crate::utils::udev::enumerate_devices;
 #[cfg(not(feature = "local"))]
 use {
-    crate::binder_service::SettingsService, binder_tokio::TokioRuntime,
+    crate::auth::Allowlist, crate::binder_service::SettingsService,
+    crate::listener::ListenerRegistry, binder_tokio::TokioRuntime,
     gamekeyd_aidl::aidl::org::ingres::gamekeys::ISettingsService::BnSettingsService,
     gamekeyd_aidl::binder::BinderFeatures, log::LevelFilter, std::error::Error,
 };
 
+mod action;
+mod auth;
+#[cfg(feature = "local")]
+mod control;
+mod display;
+mod dump;
 mod gamekey;
+mod inject;
+#[cfg(not(feature = "local"))]
+mod listener;
+mod macro_runner;
+mod profile;
+mod settings;
+mod status;
+mod storage;
+mod suppression;
 
 #[cfg(not(feature = "local"))]
 mod binder_service;
@@ -24,13 +55,124 @@ mod binder_service;
 mod fts;
 mod touch_emulator;
 mod touch_merger;
+mod touch_state;
 mod utils;
 
-pub type GameKeyData = Option<(i32, i32)>;
+const TRIGGER_COUNT: usize = 2;
+/// Slot of the binding fired by pressing both triggers together.
+const CHORD_SLOT: u32 = TRIGGER_COUNT as u32;
+/// Triggers plus the chord, each of them runs its actions on its own emulator slots.
+const ACTION_SLOT_COUNT: usize = TRIGGER_COUNT + 1;
+/// Real contacts this fraction of the panel width around an emulated touch are suppressed.
+const SUPPRESSION_RADIUS_DIVISOR: i32 = 20;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameKeyData {
+    pub action: Option<Action>,
+    pub turbo: Option<TurboMode>,
+    pub long_press: Option<Action>,
+    pub double_press: Option<Action>,
+}
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct GameKeyCompound {
     pub upper: GameKeyData,
     pub lower: GameKeyData,
+    pub chord: GameKeyData,
+    pub gestures: GestureTiming,
+}
+
+/// Slots the settings service suspended, their bindings stay as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PauseState {
+    pub all: bool,
+    pub slots: [bool; ACTION_SLOT_COUNT],
+}
+
+impl PauseState {
+    pub fn is_paused(&self, slot: u32) -> bool {
+        self.all || self.slots.get(slot as usize).copied().unwrap_or(false)
+    }
+}
+
+impl GameKeyData {
+    pub fn action_for(&self, gesture: Gesture) -> Option<&Action> {
+        match gesture {
+            Gesture::Press => self.action.as_ref(),
+            Gesture::LongPress => self.long_press.as_ref(),
+            Gesture::DoublePress => self.double_press.as_ref(),
+        }
+    }
+
+    /// Whether pressing the trigger does anything.
+    pub fn is_enabled(&self) -> bool {
+        self.action.is_some() || self.long_press.is_some() || self.double_press.is_some()
+    }
+
+    pub fn action_for_mut(&mut self, gesture: Gesture) -> &mut Option<Action> {
+        match gesture {
+            Gesture::Press => &mut self.action,
+            Gesture::LongPress => &mut self.long_press,
+            Gesture::DoublePress => &mut self.double_press,
+        }
+    }
+}
+
+impl GameKeyCompound {
+    pub fn get(&self, slot: u32) -> GameKeyData {
+        match slot {
+            0 => self.upper.clone(),
+            1 => self.lower.clone(),
+            CHORD_SLOT => self.chord.clone(),
+            _ => GameKeyData::default(),
+        }
+    }
+
+    fn get_ref(&self, slot: u32) -> Option<&GameKeyData> {
+        match slot {
+            0 => Some(&self.upper),
+            1 => Some(&self.lower),
+            CHORD_SLOT => Some(&self.chord),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, slot: u32) -> Option<&mut GameKeyData> {
+        match slot {
+            0 => Some(&mut self.upper),
+            1 => Some(&mut self.lower),
+            CHORD_SLOT => Some(&mut self.chord),
+            _ => None,
+        }
+    }
+
+    /// Whether any slot has something bound, profiles without bindings aren't kept.
+    pub fn has_bindings(&self) -> bool {
+        [&self.upper, &self.lower, &self.chord]
+            .iter()
+            .any(|data| data.is_enabled())
+    }
+
+    /// Returns the chord window, or `None` while no chord is bound.
+    pub fn chord_window(&self) -> Option<Duration> {
+        self.chord.action.as_ref().map(|_| self.gestures.chord)
+    }
+
+    pub fn gesture_config(&self, slot: u32) -> GestureConfig {
+        let Some(data) = self.get_ref(slot) else {
+            return GestureConfig::default();
+        };
+
+        GestureConfig {
+            long_press: data.long_press.as_ref().map(|_| self.gestures.long_press),
+            double_press: data
+                .double_press
+                .as_ref()
+                .map(|_| self.gestures.double_press),
+        }
+    }
 }
 
 pub struct Controller {
@@ -64,21 +206,140 @@ fn main() {
     })
 }
 
+async fn keymap_reload_loop(keymap: Arc<std::sync::RwLock<KeyMap>>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+
+    while hangup.recv().await.is_some() {
+        match KeyMap::load(KEYMAP_PATH) {
+            Ok(new_keymap) => {
+                *keymap.write().unwrap() = new_keymap;
+                log::info!("Keymap reloaded from {}", KEYMAP_PATH);
+            }
+            Err(e) => log::warn!("Keeping current keymap: {:#}", e),
+        }
+    }
+
+    Ok(())
+}
+
+async fn dispatch_gestures(
+    actions: &mut ActionRunner,
+    compound: &RwLock<GameKeyCompound>,
+    paused: &watch::Receiver<PauseState>,
+    events: Vec<GestureEvent>,
+) {
+    for ev in events {
+        log::debug!("Gesture: {:?}", ev);
+
+        if ev.pressed && paused.borrow().is_paused(ev.slot) {
+            log::debug!("Slot {} is paused, ignoring press", ev.slot);
+        } else if ev.pressed {
+            let data = compound.read().await.get(ev.slot);
+
+            if let Err(e) = actions.press(ev.slot as usize, &data, ev.gesture).await {
+                log::warn!(
+                    "Failed to start {:?} of {:?} in slot {}!",
+                    ev.gesture,
+                    data,
+                    ev.slot
+                );
+                log::warn!("{}", e);
+            }
+        } else if let Err(e) = actions.release(ev.slot as usize).await {
+            log::warn!("Failed to stop tap in slot {}!", ev.slot);
+            log::warn!("{}", e);
+        }
+    }
+}
+
+async fn dispatch_chords(
+    actions: &mut ActionRunner,
+    gestures: &mut GestureRecognizer,
+    compound: &RwLock<GameKeyCompound>,
+    paused: &watch::Receiver<PauseState>,
+    events: Vec<ChordEvent>,
+) {
+    for ev in events {
+        let events = match ev {
+            ChordEvent::Trigger(ev) => {
+                let config = compound.read().await.gesture_config(ev.slot);
+                gestures.on_event(&ev, &config, Instant::now())
+            }
+            ChordEvent::Chord { pressed } => vec![GestureEvent {
+                slot: CHORD_SLOT,
+                gesture: Gesture::Press,
+                pressed,
+            }],
+        };
+
+        dispatch_gestures(actions, compound, paused, events).await;
+    }
+}
+
 async fn gk_event_loop(
-    mut touch_emulator: TouchEmulator,
+    mut actions: ActionRunner,
     compound: Arc<RwLock<GameKeyCompound>>,
+    mut event_stream: mpsc::Receiver<Event>,
+    listeners: broadcast::Sender<Event>,
+    status: SharedStatus,
+    mut paused: watch::Receiver<PauseState>,
+    mut injected: mpsc::Receiver<Event>,
 ) -> anyhow::Result<()> {
-    let mut event_stream = read_gamekey_events().context("Get gk event stream failed")?;
+    let mut chords = ChordDetector::new();
+    let mut gestures = GestureRecognizer::new();
 
     let mut last_open_time: [SystemTime; 2] = [SystemTime::UNIX_EPOCH, SystemTime::UNIX_EPOCH];
     let mut last_close_time: [SystemTime; 2] = [SystemTime::UNIX_EPOCH, SystemTime::UNIX_EPOCH];
 
     loop {
-        let ev = event_stream.recv().await;
+        let deadline = [chords.next_deadline(), gestures.next_deadline()]
+            .into_iter()
+            .flatten()
+            .min();
+        let ev = tokio::select! {
+            ev = event_stream.recv() => ev,
+            Some(ev) = injected.recv() => {
+                log::info!("Injected {:?} of slot {}", ev.r#type, ev.slot);
+                Some(ev)
+            }
+            Ok(()) = paused.changed() => {
+                let state = *paused.borrow_and_update();
+
+                for slot in 0..ACTION_SLOT_COUNT {
+                    if !state.is_paused(slot as u32) {
+                        continue;
+                    }
+
+                    if let Err(e) = actions.cancel(slot).await {
+                        log::warn!("Failed to stop slot {} on pause: {}", slot, e);
+                    }
+                }
+
+                continue;
+            }
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let events = chords.on_timeout(Instant::now());
+                dispatch_chords(&mut actions, &mut gestures, &compound, &paused, events).await;
+
+                let events = {
+                    let compound = compound.read().await;
+                    gestures.on_timeout(|slot| compound.gesture_config(slot), Instant::now())
+                };
+
+                dispatch_gestures(&mut actions, &compound, &paused, events).await;
+                continue;
+            }
+        };
 
         if let Some(ev) = ev {
             match &ev.r#type {
+                EventType::Close if ev.slot > 1 => {}
+                EventType::Open if ev.slot > 1 => {}
                 EventType::Close => {
+                    status.write().unwrap().triggers[ev.slot as usize] = TriggerState::Closed;
+
                     let opposite_close_at = *last_close_time.get((ev.slot ^ 1) as usize).unwrap();
                     let current_close_at = last_close_time.get_mut(ev.slot as usize).unwrap();
 
@@ -93,6 +354,8 @@ async fn gk_event_loop(
                     }
                 }
                 EventType::Open => {
+                    status.write().unwrap().triggers[ev.slot as usize] = TriggerState::Open;
+
                     let opposite_open_at = *last_open_time.get((ev.slot ^ 1) as usize).unwrap();
                     let current_open_at = last_open_time.get_mut(ev.slot as usize).unwrap();
 
@@ -105,43 +368,18 @@ async fn gk_event_loop(
                         log::debug!("Both triggers are opened!");
                     }
                 }
-                EventType::Press => {
-                    let compound_lock = compound.read().await;
-
-                    let data = match ev.slot {
-                        0 => compound_lock.upper,
-                        1 => compound_lock.lower,
-                        _ => unreachable!(),
-                    };
-
-                    #[allow(clippy::collapsible_if)]
-                    if let Some((x, y)) = data {
-                        if let Err(e) = touch_emulator.start_tap(ev.slot as usize, x, y).await {
-                            log::warn!("Failed to start tap at {}, {} in slot {}!", x, y, ev.slot);
-                            log::warn!("{}", e);
-                        }
-                    }
-                }
-                EventType::Release => {
-                    let compound_lock = compound.read().await;
-
-                    let data = match ev.slot {
-                        0 => compound_lock.upper,
-                        1 => compound_lock.lower,
-                        _ => unreachable!(),
-                    };
-
-                    #[allow(clippy::collapsible_if)]
-                    if data.is_some() {
-                        if let Err(e) = touch_emulator.stop_tap(ev.slot as usize).await {
-                            log::warn!("Failed to stop tap in slot {}!", ev.slot);
-                            log::warn!("{}", e);
-                        }
-                    }
+                EventType::Press | EventType::Release => {
+                    let window = compound.read().await.chord_window();
+                    let events = chords.on_event(ev.clone(), window, Instant::now());
+
+                    dispatch_chords(&mut actions, &mut gestures, &compound, &paused, events).await;
                 }
             }
 
             log::debug!("Event: {:#?}", ev);
+            status.write().unwrap().record_event(ev.clone());
+            // nobody may be listening
+            let _ = listeners.send(ev);
             continue;
         } else {
             log::warn!("GameKey event stream is dead!");
@@ -153,29 +391,59 @@ async fn gk_event_loop(
 }
 
 async fn async_main() -> anyhow::Result<()> {
-    let compound = Arc::new(RwLock::new(
-        #[cfg(not(feature = "local"))]
-        GameKeyCompound {
-            lower: None,
-            upper: None,
-        },
-        #[cfg(feature = "local")]
-        GameKeyCompound {
-            lower: Some((1000, 2000)),
-            upper: Some((3000, 6000)),
-        },
-    ));
+    let storage = SettingsStorage::new(data_dir());
+    let (current, profiles, display) = storage.load_or_default();
+    let compound = Arc::new(RwLock::new(current));
 
     log::info!("hi probably?");
 
+    let keymap = Arc::new(std::sync::RwLock::new(KeyMap::load_or_default()));
+    let profiles = Arc::new(Mutex::new(profiles));
+    let (gk_events, _) = broadcast::channel(16);
+    let (pause, paused) = watch::channel(PauseState::default());
+    let (inject, injected) = mpsc::channel(16);
+    let status = SharedStatus::new(std::sync::RwLock::new(DaemonStatus {
+        input_devices: enumerate_devices().unwrap_or_default(),
+        ..Default::default()
+    }));
+
+    let clock = Clock::from_property();
+    let (mut touch_emulator, touch_emulator_rx) =
+        TouchEmulator::new((ACTION_SLOT_COUNT * FINGERS_PER_SLOT) as u8, clock)
+            .context("Failed to create touch emulator")?;
+    if let Some(display) = display {
+        touch_emulator
+            .display_mut()
+            .set_config(&display)
+            .context("Failed to restore the display")?;
+    }
+    let touch_emulator = Arc::new(Mutex::new(touch_emulator));
+    let actions = ActionRunner::new(touch_emulator.clone(), ACTION_SLOT_COUNT);
+
+    let controller = Arc::new(SettingsController::new(
+        compound.clone(),
+        profiles,
+        storage,
+        status.clone(),
+        touch_emulator.clone(),
+        pause,
+        inject.clone(),
+    ));
+
     #[cfg(not(feature = "local"))]
     {
         binder::ProcessState::start_thread_pool();
         log::info!("Binder thread pool has been started!");
 
+        let listeners = Arc::new(ListenerRegistry::new());
+        tokio::spawn(listener::forward_events(
+            listeners.clone(),
+            gk_events.subscribe(),
+        ));
+
         let name = "org.ingres.gamekeys.ISettingsService/default";
         let svc = BnSettingsService::new_async_binder(
-            SettingsService::new(compound.clone()),
+            SettingsService::new(controller, listeners, Allowlist::from_property()),
             binder_tokio::TokioRuntime(tokio::runtime::Handle::current()),
             BinderFeatures::default(),
         );
@@ -184,24 +452,107 @@ async fn async_main() -> anyhow::Result<()> {
         log::info!("Binder service '{}' registered successfully!", name);
     }
 
-    let (touch_emulator, touch_emulator_rx) =
-        TouchEmulator::new(2).context("Failed to create touch emulator")?;
+    #[cfg(feature = "local")]
+    {
+        tokio::spawn(async move {
+            if let Err(e) = inject::read_stdin(inject).await {
+                log::error!("Failed to read injected events: {:#}", e);
+            }
+        });
+
+        let events = gk_events.clone();
+        tokio::spawn(async move {
+            let path = data_dir().join(control::CONTROL_SOCKET);
+
+            if let Err(e) = control::serve(&path, controller, events).await {
+                log::error!("Control socket failed: {:#}", e);
+            }
+        });
+    }
+
+    let (fts_path, fts_caps, fts_rx) =
+        read_fts_events(clock).context("Failed to get fts input event stream")?;
+    {
+        let mut status = status.write().unwrap();
+        status.touch_device = Some(fts_path);
+        status.touch_grabbed = true;
+    }
 
-    let fts_rx = read_fts_events().context("Failed to get fts input event stream")?;
+    log::info!(
+        "Touchscreen range is {}..{} x {}..{}",
+        fts_caps.x.minimum,
+        fts_caps.x.maximum,
+        fts_caps.y.minimum,
+        fts_caps.y.maximum
+    );
+    {
+        let mut touch_emulator = touch_emulator.lock().await;
+        touch_emulator
+            .display_mut()
+            .set_panel(
+                (fts_caps.x.minimum, fts_caps.x.maximum),
+                (fts_caps.y.minimum, fts_caps.y.maximum),
+            )
+            .context("Failed to map the display onto the touchscreen")?;
+
+        // InputReader takes touches without pressure for hovering
+        if let Some(pressure) = fts_caps.abs_info(EV_ABS::ABS_MT_PRESSURE) {
+            touch_emulator.set_pressure(Some((pressure.minimum + pressure.maximum) / 2));
+        }
+    }
 
-    let touch_merger = TouchMerger::new(Box::from([
-        (TouchSourceDeclaration::new(10), fts_rx),
-        (TouchSourceDeclaration::new(2), touch_emulator_rx),
-    ]))
-    .context("Failed to create Touch Merger")?;
+    let suppression = SuppressionConfig::from_properties(
+        (fts_caps.x.maximum - fts_caps.x.minimum) / SUPPRESSION_RADIUS_DIVISOR,
+    );
+    let (touch_merger, merger) = TouchMerger::new(&fts_caps, suppression, clock, status.clone())
+        .context("Failed to create Touch Merger")?;
+    status.write().unwrap().merged_device = touch_merger.devnode();
+    let touch_merger = tokio::spawn(touch_merger.processing_task());
+
+    merger
+        .attach(
+            TouchSourceDeclaration::new(fts_caps.slot_count, SourceKind::Touchscreen),
+            fts_rx,
+        )
+        .await
+        .context("Failed to attach the touchscreen")?;
+    merger
+        .attach(
+            TouchSourceDeclaration::new(
+                (ACTION_SLOT_COUNT * FINGERS_PER_SLOT) as i32,
+                SourceKind::Emulated,
+            ),
+            touch_emulator_rx,
+        )
+        .await
+        .context("Failed to attach the touch emulator")?;
+
+    let (gk_path, gk_stream) =
+        read_gamekey_events(keymap.clone()).context("Get gk event stream failed")?;
+    status.write().unwrap().gamekey_device = Some(gk_path);
+
+    // without reloads the current keymap stays, emulation goes on either way
+    tokio::spawn(async move {
+        if let Err(e) = keymap_reload_loop(keymap).await {
+            log::error!("Keymap reloads are unavailable: {:#}", e);
+        }
+    });
 
     tokio::select! {
-        res = touch_merger.processing_task() => {
-            if res.is_err() {
+        res = touch_merger => {
+            if !matches!(res, Ok(Ok(()))) {
                 log::error!("An error occurred while merging input events: {:#?}", res);
             }
         }
-        res = gk_event_loop(touch_emulator, compound.clone()) => {
+        res = gk_event_loop(
+            actions,
+            compound.clone(),
+            gk_stream,
+            gk_events,
+            status,
+            paused,
+            injected,
+        ) => {
             if res.is_err() {
                 log::error!("An error occurred while reading input events from gamekey device: {:#?}", res);
             }
@@ -210,3 +561,107 @@ async fn async_main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::EventCode;
+    use evdev_rs::{InputEvent, TimeVal};
+    use tokio::time::sleep;
+
+    const TRACKING_ID: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID);
+
+    fn event(r#type: EventType, slot: u32) -> Event {
+        Event {
+            r#type,
+            slot,
+            time: TimeVal::new(0, 0),
+        }
+    }
+
+    async fn collect(mut rx: mpsc::Receiver<InputEvent>) -> Vec<(EventCode, i32)> {
+        let mut events = Vec::new();
+
+        while let Some(ev) = rx.recv().await {
+            events.push((ev.event_code, ev.value));
+        }
+
+        events
+    }
+
+    #[test]
+    fn pauses_all_or_single_slots() {
+        let mut state = PauseState::default();
+        assert!(!state.is_paused(0));
+
+        state.slots[1] = true;
+        assert!(!state.is_paused(0));
+        assert!(state.is_paused(1));
+        // slots the daemon doesn't know never are
+        assert!(!state.is_paused(ACTION_SLOT_COUNT as u32));
+
+        state.all = true;
+        assert!(state.is_paused(0));
+        assert!(state.is_paused(CHORD_SLOT));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_presses_while_paused() {
+        let (emulator, rx) = TouchEmulator::new(
+            (ACTION_SLOT_COUNT * FINGERS_PER_SLOT) as u8,
+            Clock::default(),
+        )
+        .unwrap();
+        let touches = tokio::spawn(collect(rx));
+        let actions = ActionRunner::new(Arc::new(Mutex::new(emulator)), ACTION_SLOT_COUNT);
+
+        let compound = GameKeyCompound {
+            upper: GameKeyData {
+                action: Some(Action::Tap { x: 100, y: 100 }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (events, event_stream) = mpsc::channel(16);
+        let (_inject, injected) = mpsc::channel(1);
+        let (pause, paused) = watch::channel(PauseState {
+            all: true,
+            ..Default::default()
+        });
+
+        let event_loop = tokio::spawn(gk_event_loop(
+            actions,
+            Arc::new(RwLock::new(compound)),
+            event_stream,
+            broadcast::channel(16).0,
+            SharedStatus::default(),
+            paused,
+            injected,
+        ));
+
+        let press = || async {
+            events.send(event(EventType::Press, 0)).await.unwrap();
+            sleep(Duration::from_millis(10)).await;
+            events.send(event(EventType::Release, 0)).await.unwrap();
+            sleep(Duration::from_millis(10)).await;
+        };
+
+        press().await;
+
+        pause.send(PauseState::default()).unwrap();
+        press().await;
+
+        drop(events);
+        event_loop.await.unwrap().unwrap();
+
+        let touches = touches.await.unwrap();
+        assert_eq!(
+            touches.iter().filter(|ev| ev.0 == TRACKING_ID).count(),
+            2,
+            "{:?}",
+            touches
+        );
+        assert!(touches.contains(&(TRACKING_ID, 0)));
+        assert!(!touches.contains(&(TRACKING_ID, 1)));
+    }
+}
@@ -0,0 +1,162 @@
+// only the binder service switches profiles
+#![cfg_attr(feature = "local", allow(dead_code))]
+
+use crate::GameKeyCompound;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Bindings of every application, keyed by package name.
+///
+/// The active profile lives in the shared [`GameKeyCompound`], so the setters always edit
+/// it, and is put back into the store when another profile is activated. Settings made
+/// before any profile was activated are dropped on the first activation, profiles left
+/// without bindings are dropped when switching away from them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProfileStore {
+    profiles: HashMap<String, GameKeyCompound>,
+    active: Option<String>,
+}
+
+impl ProfileStore {
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Makes the profile of `package` the current one, packages without a profile start
+    /// with nothing bound.
+    pub fn activate(&mut self, package: &str, current: &mut GameKeyCompound) {
+        if self.active.as_deref() == Some(package) {
+            return;
+        }
+
+        // every foreground app is activated, most of them never get bindings
+        if let Some(active) = self.active.take().filter(|_| current.has_bindings()) {
+            self.profiles.insert(active, current.clone());
+        }
+
+        *current = self.profiles.remove(package).unwrap_or_default();
+        self.active = Some(package.to_string());
+
+        log::info!("Activated profile of {}", package);
+    }
+
+    pub fn remove(&mut self, package: &str, current: &mut GameKeyCompound) {
+        if self.active.as_deref() == Some(package) {
+            *current = GameKeyCompound::default();
+            self.active = None;
+        } else {
+            self.profiles.remove(package);
+        }
+    }
+
+    /// Returns the stored profiles, without the active one.
+    pub fn profiles(&self) -> impl Iterator<Item = &GameKeyCompound> {
+        self.profiles.values()
+    }
+
+    pub fn packages(&self) -> Vec<String> {
+        let mut packages: Vec<String> = self
+            .profiles
+            .keys()
+            .chain(self.active.iter())
+            .cloned()
+            .collect();
+        packages.sort();
+
+        packages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+
+    fn bound(x: i32) -> GameKeyCompound {
+        let mut compound = GameKeyCompound::default();
+        compound.upper.action = Some(Action::Tap { x, y: 0 });
+
+        compound
+    }
+
+    fn upper_x(compound: &GameKeyCompound) -> Option<i32> {
+        match compound.upper.action {
+            Some(Action::Tap { x, .. }) => Some(x),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn switches_profiles() {
+        let mut store = ProfileStore::default();
+        let mut current = bound(1);
+
+        // settings from before the first activation are dropped
+        store.activate("org.example.a", &mut current);
+        assert_eq!(upper_x(&current), None);
+        assert_eq!(store.active(), Some("org.example.a"));
+
+        current = bound(2);
+        store.activate("org.example.b", &mut current);
+        current = bound(3);
+        store.activate("org.example.a", &mut current);
+        assert_eq!(upper_x(&current), Some(2));
+
+        store.activate("org.example.b", &mut current);
+        assert_eq!(upper_x(&current), Some(3));
+        assert_eq!(store.packages(), vec!["org.example.a", "org.example.b"]);
+    }
+
+    #[test]
+    fn activating_again_keeps_current() {
+        let mut store = ProfileStore::default();
+        let mut current = GameKeyCompound::default();
+
+        store.activate("org.example.a", &mut current);
+        current = bound(1);
+        store.activate("org.example.a", &mut current);
+
+        assert_eq!(upper_x(&current), Some(1));
+    }
+
+    #[test]
+    fn drops_profiles_without_bindings() {
+        let mut store = ProfileStore::default();
+        let mut current = GameKeyCompound::default();
+
+        store.activate("org.example.launcher", &mut current);
+        store.activate("org.example.game", &mut current);
+        current = bound(1);
+        store.activate("org.example.browser", &mut current);
+
+        assert_eq!(store.profiles().count(), 1);
+        assert_eq!(
+            store.packages(),
+            vec!["org.example.browser", "org.example.game"]
+        );
+    }
+
+    #[test]
+    fn removes_profiles() {
+        let mut store = ProfileStore::default();
+        let mut current = GameKeyCompound::default();
+
+        store.activate("org.example.a", &mut current);
+        current = bound(1);
+        store.activate("org.example.b", &mut current);
+        current = bound(2);
+
+        store.remove("org.example.a", &mut current);
+        assert_eq!(upper_x(&current), Some(2));
+        assert_eq!(store.packages(), vec!["org.example.b"]);
+
+        store.remove("org.example.b", &mut current);
+        assert_eq!(upper_x(&current), None);
+        assert_eq!(store.active(), None);
+        assert!(store.packages().is_empty());
+
+        // nothing of the removed profile comes back
+        store.activate("org.example.a", &mut current);
+        assert_eq!(store.profiles().count(), 0);
+    }
+}
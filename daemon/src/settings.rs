@@ -0,0 +1,279 @@
+use crate::action::{Action, TurboMode, FINGERS_PER_SLOT};
+use crate::display::Rotation;
+use crate::dump::{write_dump, DumpState};
+use crate::gamekey::gesture::Gesture;
+use crate::gamekey::{Event, EventType};
+use crate::inject::synthetic_event;
+use crate::profile::ProfileStore;
+use crate::status::{DaemonStatus, SharedStatus};
+use crate::storage::SettingsStorage;
+use crate::touch_emulator::TouchEmulator;
+use crate::{GameKeyCompound, GameKeyData, PauseState, ACTION_SLOT_COUNT};
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex, RwLock};
+
+/// Operations of the settings service, shared by binder and the local control socket.
+pub struct SettingsController {
+    compound: Arc<RwLock<GameKeyCompound>>,
+    profiles: Arc<Mutex<ProfileStore>>,
+    storage: SettingsStorage,
+    status: SharedStatus,
+    emulator: Arc<Mutex<TouchEmulator>>,
+    pause: watch::Sender<PauseState>,
+    inject: mpsc::Sender<Event>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidArgument(String),
+    EventLoopGone,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            Error::EventLoopGone => write!(f, "Event loop is gone!"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn invalid(msg: impl ToString) -> Error {
+    Error::InvalidArgument(msg.to_string())
+}
+
+fn slot_data(compound: &mut GameKeyCompound, slot: i32) -> Result<&mut GameKeyData, Error> {
+    u32::try_from(slot)
+        .ok()
+        .and_then(|slot| compound.get_mut(slot))
+        .ok_or_else(|| invalid("invalid slot"))
+}
+
+/// Checks what the emulator can't play, clients may send anything.
+fn validate_action(action: &Action) -> Result<(), Error> {
+    match action {
+        Action::Macro(macro_) => macro_.validate(FINGERS_PER_SLOT).map_err(invalid),
+        _ => Ok(()),
+    }
+}
+
+impl SettingsController {
+    pub fn new(
+        compound: Arc<RwLock<GameKeyCompound>>,
+        profiles: Arc<Mutex<ProfileStore>>,
+        storage: SettingsStorage,
+        status: SharedStatus,
+        emulator: Arc<Mutex<TouchEmulator>>,
+        pause: watch::Sender<PauseState>,
+        inject: mpsc::Sender<Event>,
+    ) -> Self {
+        Self {
+            compound,
+            profiles,
+            storage,
+            status,
+            emulator,
+            pause,
+            inject,
+        }
+    }
+
+    /// Saves the settings, a failure leaves them applied until the daemon restarts.
+    ///
+    /// Locks the profiles, the settings and the emulator in that order, every method taking
+    /// more than one of them has to keep it.
+    async fn save(&self) {
+        let profiles = self.profiles.lock().await;
+        let compound = self.compound.read().await;
+        let display = self.emulator.lock().await.display().config();
+
+        if let Err(e) = self.storage.save(&compound, &profiles, &display) {
+            log::error!("Failed to save settings: {:#}", e);
+        }
+    }
+
+    pub async fn settings(&self) -> GameKeyCompound {
+        self.compound.read().await.clone()
+    }
+
+    /// Binds taps to the triggers, `None` unbinds them.
+    pub async fn set_taps(&self, upper: Option<(i32, i32)>, lower: Option<(i32, i32)>) {
+        {
+            let mut compound = self.compound.write().await;
+            compound.upper.action = upper.map(|(x, y)| Action::Tap { x, y });
+            compound.lower.action = lower.map(|(x, y)| Action::Tap { x, y });
+        }
+
+        self.save().await;
+    }
+
+    pub async fn set_action(
+        &self,
+        slot: i32,
+        gesture: Gesture,
+        action: Option<Action>,
+    ) -> Result<(), Error> {
+        if let Some(action) = &action {
+            validate_action(action)?;
+        }
+
+        *slot_data(&mut *self.compound.write().await, slot)?.action_for_mut(gesture) = action;
+
+        self.save().await;
+
+        Ok(())
+    }
+
+    pub async fn set_turbo(&self, slot: i32, turbo: Option<TurboMode>) -> Result<(), Error> {
+        let turbo = turbo
+            .map(|turbo| TurboMode::new(turbo.taps_per_second, turbo.duty_cycle))
+            .transpose()
+            .map_err(invalid)?;
+
+        slot_data(&mut *self.compound.write().await, slot)?.turbo = turbo;
+
+        self.save().await;
+
+        Ok(())
+    }
+
+    pub async fn set_gesture_timing(
+        &self,
+        long_press: Duration,
+        double_press: Duration,
+    ) -> Result<(), Error> {
+        if long_press.is_zero() || double_press.is_zero() {
+            return Err(invalid("gesture timing must not be zero"));
+        }
+
+        {
+            let mut compound = self.compound.write().await;
+            compound.gestures.long_press = long_press;
+            compound.gestures.double_press = double_press;
+        }
+
+        self.save().await;
+
+        Ok(())
+    }
+
+    pub async fn set_chord_window(&self, window: Duration) -> Result<(), Error> {
+        if window.is_zero() {
+            return Err(invalid("chord window must not be zero"));
+        }
+
+        self.compound.write().await.gestures.chord = window;
+
+        self.save().await;
+
+        Ok(())
+    }
+
+    pub async fn activate_profile(&self, package: &str) {
+        self.profiles
+            .lock()
+            .await
+            .activate(package, &mut *self.compound.write().await);
+
+        self.save().await;
+    }
+
+    pub async fn remove_profile(&self, package: &str) {
+        self.profiles
+            .lock()
+            .await
+            .remove(package, &mut *self.compound.write().await);
+
+        self.save().await;
+    }
+
+    pub async fn profiles(&self) -> Vec<String> {
+        self.profiles.lock().await.packages()
+    }
+
+    /// Returns the status and the emulator slots touching right now.
+    pub async fn status(&self) -> (DaemonStatus, Vec<usize>) {
+        let active_slots = self.emulator.lock().await.active_slots();
+
+        (self.status.read().unwrap().clone(), active_slots)
+    }
+
+    pub async fn set_display(
+        &self,
+        width: i32,
+        height: i32,
+        rotation: Rotation,
+    ) -> Result<(), Error> {
+        self.emulator
+            .lock()
+            .await
+            .display_mut()
+            .set_display(width, height, rotation)
+            .map_err(invalid)?;
+
+        // bound points are display pixels, they need the same display after a restart
+        self.save().await;
+
+        Ok(())
+    }
+
+    pub async fn set_rotation(&self, rotation: Rotation) {
+        self.emulator
+            .lock()
+            .await
+            .display_mut()
+            .set_rotation(rotation);
+
+        self.save().await;
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.pause.send_modify(|state| state.all = paused);
+    }
+
+    pub fn set_slot_paused(&self, slot: i32, paused: bool) -> Result<(), Error> {
+        let slot = usize::try_from(slot)
+            .ok()
+            .filter(|slot| *slot < ACTION_SLOT_COUNT)
+            .ok_or_else(|| invalid("invalid slot"))?;
+
+        self.pause.send_modify(|state| state.slots[slot] = paused);
+
+        Ok(())
+    }
+
+    pub async fn inject_event(&self, r#type: EventType, slot: i32) -> Result<(), Error> {
+        let slot = u32::try_from(slot).map_err(|_| invalid("invalid slot"))?;
+        let ev = synthetic_event(r#type, slot).map_err(invalid)?;
+
+        self.inject.send(ev).await.map_err(|_| Error::EventLoopGone)
+    }
+
+    /// Writes the state for `dumpsys`, blocks on the locks so it must not run on the runtime.
+    ///
+    /// The locks are taken in the order of [`Self::save`].
+    pub fn write_dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        let profiles = self.profiles.blocking_lock();
+        let compound = self.compound.blocking_read();
+        let active_slots = self.emulator.blocking_lock().active_slots();
+        let paused = *self.pause.borrow();
+        let status = self.status.read().unwrap();
+
+        write_dump(
+            out,
+            &DumpState {
+                compound: &compound,
+                profile: profiles.active(),
+                paused: &paused,
+                status: &status,
+                active_slots: &active_slots,
+            },
+        )
+    }
+}
@@ -0,0 +1,71 @@
+use crate::gamekey::Event;
+use crate::touch_state::SlotState;
+use crate::TRIGGER_COUNT;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Gamekey events kept for `dumpsys`.
+const EVENT_HISTORY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TriggerState {
+    /// Not moved since the daemon started.
+    #[default]
+    Unknown,
+    Open,
+    Closed,
+}
+
+/// What the merger knows about one of its sources.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceStatus {
+    pub id: usize,
+    /// First merged slot of the source, `slot_count` of them are its own.
+    pub first_slot: i32,
+    pub slot_count: i32,
+    pub current_slot: i32,
+    pub in_touch: bool,
+    /// Events waiting for the `SYN_REPORT` of their frame.
+    pub buffered: usize,
+}
+
+/// State of the touch merger as of the last frame it wrote.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MergerStatus {
+    pub sources: Vec<SourceStatus>,
+    /// Merged slots with a contact and what touches them.
+    pub contacts: Vec<(i32, SlotState)>,
+    pub current_slot: i32,
+    pub next_tracking_id: i32,
+}
+
+/// What the daemon found and did at startup, and where the triggers are.
+#[derive(Debug, Clone, Default)]
+pub struct DaemonStatus {
+    /// Every evdev node with its name, as seen at startup.
+    pub input_devices: Vec<(PathBuf, String)>,
+    pub gamekey_device: Option<PathBuf>,
+    pub touch_device: Option<PathBuf>,
+    pub touch_grabbed: bool,
+    /// Node of the `gamekey-touch` uinput device.
+    pub merged_device: Option<String>,
+    pub triggers: [TriggerState; TRIGGER_COUNT],
+    pub merger: MergerStatus,
+    /// Latest gamekey events, oldest first.
+    pub recent_events: VecDeque<Event>,
+}
+
+impl DaemonStatus {
+    pub fn record_event(&mut self, ev: Event) {
+        if self.recent_events.len() == EVENT_HISTORY_LEN {
+            self.recent_events.pop_front();
+        }
+
+        self.recent_events.push_back(ev);
+    }
+}
+
+pub type SharedStatus = Arc<RwLock<DaemonStatus>>;
@@ -0,0 +1,304 @@
+use crate::action::{Action, TurboMode, FINGERS_PER_SLOT};
+use crate::display::DisplayConfig;
+use crate::profile::ProfileStore;
+use crate::{GameKeyCompound, GameKeyData};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Bumped on every change old daemons can't read.
+const FORMAT_VERSION: u32 = 2;
+const SETTINGS_FILE: &str = "settings.json";
+
+#[cfg(not(feature = "local"))]
+const DATA_DIR_PROP: &str = "ro.vendor.gamekeyd.data_dir";
+#[cfg(not(feature = "local"))]
+const DEFAULT_DATA_DIR: &str = "/data/vendor/gamekeyd";
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Serialize)]
+struct SettingsFileRef<'a> {
+    version: u32,
+    current: &'a GameKeyCompound,
+    profiles: &'a ProfileStore,
+    display: &'a DisplayConfig,
+}
+
+#[derive(Deserialize)]
+struct SettingsFile {
+    current: GameKeyCompound,
+    profiles: ProfileStore,
+    /// Missing until the display was first declared.
+    #[serde(default)]
+    display: Option<DisplayConfig>,
+}
+
+/// Settings, profiles and the last declared display, as kept in the file.
+pub type Settings = (GameKeyCompound, ProfileStore, Option<DisplayConfig>);
+
+/// Keeps the settings and profiles in a file, so they survive restarts of the daemon.
+pub struct SettingsStorage {
+    path: PathBuf,
+}
+
+/// Returns the directory the settings are kept in, `ro.vendor.gamekeyd.data_dir` overrides
+/// the default one.
+#[cfg(not(feature = "local"))]
+pub fn data_dir() -> PathBuf {
+    match rustutils::system_properties::read(DATA_DIR_PROP) {
+        Ok(Some(dir)) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(DEFAULT_DATA_DIR),
+    }
+}
+
+#[cfg(feature = "local")]
+pub fn data_dir() -> PathBuf {
+    std::env::temp_dir().join("gamekeyd")
+}
+
+fn validate_data(data: &GameKeyData) -> anyhow::Result<()> {
+    if let Some(turbo) = data.turbo {
+        TurboMode::new(turbo.taps_per_second, turbo.duty_cycle)?;
+    }
+
+    for action in [&data.action, &data.long_press, &data.double_press]
+        .into_iter()
+        .flatten()
+    {
+        if let Action::Macro(macro_) = action {
+            macro_.validate(FINGERS_PER_SLOT)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks what the setters would have refused, a damaged file may still parse.
+fn validate(compound: &GameKeyCompound) -> anyhow::Result<()> {
+    for data in [&compound.upper, &compound.lower, &compound.chord] {
+        validate_data(data)?;
+    }
+
+    if compound.gestures.long_press.is_zero()
+        || compound.gestures.double_press.is_zero()
+        || compound.gestures.chord.is_zero()
+    {
+        bail!("Gesture timing is zero");
+    }
+
+    Ok(())
+}
+
+impl SettingsStorage {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            path: dir.as_ref().join(SETTINGS_FILE),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Settings> {
+        let header: Header = serde_json::from_str(text).context("Missing format version")?;
+
+        if header.version != FORMAT_VERSION {
+            bail!("Unsupported format version {}", header.version);
+        }
+
+        let file: SettingsFile = serde_json::from_str(text)?;
+
+        validate(&file.current)?;
+        for profile in file.profiles.profiles() {
+            validate(profile)?;
+        }
+
+        if file
+            .display
+            .is_some_and(|display| display.width <= 0 || display.height <= 0)
+        {
+            bail!("Display size is not positive");
+        }
+
+        Ok((file.current, file.profiles, file.display))
+    }
+
+    /// Returns `None` if nothing was saved yet.
+    pub fn load(&self) -> anyhow::Result<Option<Settings>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.path.display()))
+            }
+        };
+
+        Self::parse(&text)
+            .with_context(|| format!("Failed to parse {}", self.path.display()))
+            .map(Some)
+    }
+
+    /// Loads the saved settings, starting over with nothing bound if they are unreadable.
+    ///
+    /// A damaged file is kept next to the new one for inspection.
+    pub fn load_or_default(&self) -> Settings {
+        match self.load() {
+            Ok(Some(settings)) => {
+                log::info!("Settings restored from {}", self.path.display());
+                settings
+            }
+            Ok(None) => Default::default(),
+            Err(e) => {
+                log::error!("Discarding saved settings: {:#}", e);
+
+                let backup = self.path.with_extension("json.bad");
+                if let Err(e) = fs::rename(&self.path, &backup) {
+                    log::warn!("Failed to keep damaged settings: {}", e);
+                }
+
+                Default::default()
+            }
+        }
+    }
+
+    /// Replaces the saved settings, a crash in the middle leaves the old file intact.
+    pub fn save(
+        &self,
+        current: &GameKeyCompound,
+        profiles: &ProfileStore,
+        display: &DisplayConfig,
+    ) -> anyhow::Result<()> {
+        let text = serde_json::to_string_pretty(&SettingsFileRef {
+            version: FORMAT_VERSION,
+            current,
+            profiles,
+            display,
+        })?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        let tmp_path = self.path.with_extension("json.tmp");
+        let mut tmp = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        tmp.write_all(text.as_bytes())?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace {}", self.path.display()))?;
+
+        // make the rename itself durable
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::{DisplayTransform, Rotation};
+    use crate::utils::temp_dir::TempDir;
+
+    fn bound_compound() -> GameKeyCompound {
+        let mut compound = GameKeyCompound::default();
+        compound.upper.action = Some(Action::Tap { x: 100, y: 200 });
+        compound.lower.turbo = Some(TurboMode::new(10, 50).unwrap());
+
+        compound
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("round-trip");
+        let storage = SettingsStorage::new(&*dir);
+        let mut current = bound_compound();
+        let mut profiles = ProfileStore::default();
+        profiles.activate("org.example.game", &mut current);
+        current.chord.action = Some(Action::Tap { x: 1, y: 2 });
+
+        let display = DisplayConfig {
+            width: 1440,
+            height: 3200,
+            rotation: Rotation::Rotation90,
+        };
+
+        storage.save(&current, &profiles, &display).unwrap();
+        let (loaded, loaded_profiles, loaded_display) = storage.load().unwrap().unwrap();
+
+        assert_eq!(loaded.chord, current.chord);
+        assert_eq!(loaded.upper, current.upper);
+        assert_eq!(loaded_profiles.active(), Some("org.example.game"));
+        assert_eq!(loaded_profiles.packages(), profiles.packages());
+        assert_eq!(loaded_display, Some(display));
+    }
+
+    #[test]
+    fn missing_file_is_not_an_error() {
+        let dir = TempDir::new("missing");
+        let storage = SettingsStorage::new(&*dir);
+
+        assert!(storage.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let err = SettingsStorage::parse(r#"{"version": 999, "current": {}, "profiles": {}}"#)
+            .unwrap_err();
+
+        assert!(err.to_string().contains("999"));
+    }
+
+    #[test]
+    fn reads_files_without_display() {
+        let text = r#"{
+            "version": 2,
+            "current": {},
+            "profiles": { "profiles": {}, "active": null }
+        }"#;
+
+        assert_eq!(SettingsStorage::parse(text).unwrap().2, None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let text = r#"{
+            "version": 2,
+            "current": { "upper": { "turbo": { "taps_per_second": 0, "duty_cycle": 50 } } },
+            "profiles": { "profiles": {}, "active": null }
+        }"#;
+
+        assert!(SettingsStorage::parse(text).is_err());
+    }
+
+    #[test]
+    fn falls_back_on_corruption() {
+        let dir = TempDir::new("corrupt");
+        let storage = SettingsStorage::new(&*dir);
+        storage
+            .save(
+                &bound_compound(),
+                &ProfileStore::default(),
+                &DisplayTransform::default().config(),
+            )
+            .unwrap();
+        fs::write(&storage.path, "{\"version\": 2, \"curr").unwrap();
+
+        let (current, profiles, display) = storage.load_or_default();
+
+        assert_eq!(current.upper, GameKeyData::default());
+        assert!(profiles.packages().is_empty());
+        assert_eq!(display, None);
+        assert!(!storage.path.exists());
+        assert!(storage.path.with_extension("json.bad").exists());
+    }
+}
@@ -0,0 +1,381 @@
+use crate::action::FINGERS_PER_SLOT;
+use crate::touch_state::SlotState;
+use crate::ACTION_SLOT_COUNT;
+use anyhow::{bail, Context};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::str::FromStr;
+
+#[cfg(not(feature = "local"))]
+const SUPPRESSION_MODE_PROP: &str = "ro.vendor.gamekeyd.suppression_mode";
+#[cfg(not(feature = "local"))]
+const SUPPRESSION_RADIUS_PROP: &str = "ro.vendor.gamekeyd.suppression_radius";
+#[cfg(not(feature = "local"))]
+const SUPPRESSION_ZONES_PROP: &str = "ro.vendor.gamekeyd.suppression_zones";
+
+/// Area of the touch panel, bounds included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+/// What happens to real contacts in an active zone.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SuppressionMode {
+    /// Hidden until the finger lifts, even once the zone is free again.
+    #[default]
+    Drop,
+    /// Hidden while in an active zone, shown as a new contact after.
+    Defer,
+}
+
+/// Rectangle suppressing real contacts while an emulated source touches any of `slots`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuppressionZone {
+    pub rect: Rect,
+    pub slots: Range<usize>,
+}
+
+/// Where real contacts are kept from the merged device, so games don't see a finger resting
+/// next to a point the triggers tap.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SuppressionConfig {
+    pub mode: SuppressionMode,
+    /// Every emulated contact suppresses this far around it in panel units, 0 turns it off.
+    pub radius: i32,
+    pub zones: Vec<SuppressionZone>,
+}
+
+/// Suppression state of one real source.
+#[derive(Debug, Default)]
+pub struct Suppressor {
+    /// Source slots and tracking ids of dropped contacts.
+    dropped: HashSet<(usize, i32)>,
+}
+
+impl Rect {
+    pub fn around(x: i32, y: i32, radius: i32) -> Self {
+        Self {
+            left: x - radius,
+            top: y - radius,
+            right: x + radius,
+            bottom: y + radius,
+        }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        (self.left..=self.right).contains(&x) && (self.top..=self.bottom).contains(&y)
+    }
+}
+
+impl FromStr for SuppressionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(SuppressionMode::Drop),
+            "defer" => Ok(SuppressionMode::Defer),
+            _ => bail!("Unknown suppression mode {:?}", s),
+        }
+    }
+}
+
+// only the device reads zones from its properties
+#[cfg_attr(feature = "local", allow(dead_code))]
+impl SuppressionZone {
+    /// Parses zones in panel units, each active while the trigger `slot` touches:
+    ///
+    /// ```text
+    /// <slot>:<left>,<top>,<right>,<bottom>;...
+    /// ```
+    pub fn parse_list(text: &str) -> anyhow::Result<Vec<Self>> {
+        text.split(';')
+            .map(str::trim)
+            .filter(|zone| !zone.is_empty())
+            .map(|zone| Self::parse(zone).with_context(|| format!("Invalid zone `{}`", zone)))
+            .collect()
+    }
+
+    fn parse(zone: &str) -> anyhow::Result<Self> {
+        let (slot, rect) = zone.split_once(':').context("Missing slot")?;
+        let slot: usize = slot.trim().parse().context("Invalid slot")?;
+
+        if slot >= ACTION_SLOT_COUNT {
+            bail!("Invalid slot {}", slot);
+        }
+
+        let values = rect
+            .split(',')
+            .map(|value| value.trim().parse())
+            .collect::<Result<Vec<i32>, _>>()
+            .context("Invalid bounds")?;
+        let [left, top, right, bottom] = values[..] else {
+            bail!("Expected 4 bounds, got {}", values.len());
+        };
+
+        if left > right || top > bottom {
+            bail!("Empty rectangle");
+        }
+
+        Ok(Self {
+            rect: Rect {
+                left,
+                top,
+                right,
+                bottom,
+            },
+            // the emulator's fingers of the trigger
+            slots: slot * FINGERS_PER_SLOT..(slot + 1) * FINGERS_PER_SLOT,
+        })
+    }
+}
+
+impl SuppressionConfig {
+    /// Reads the mode from `ro.vendor.gamekeyd.suppression_mode`, the radius from
+    /// `ro.vendor.gamekeyd.suppression_radius` and the zones from
+    /// `ro.vendor.gamekeyd.suppression_zones`, falling back to drop, `radius` and none.
+    #[cfg(not(feature = "local"))]
+    pub fn from_properties(radius: i32) -> Self {
+        use rustutils::system_properties::read;
+
+        let mode = match read(SUPPRESSION_MODE_PROP) {
+            Ok(Some(text)) if !text.is_empty() => text.parse().unwrap_or_else(|e| {
+                log::error!("Ignoring {}: {:#}", SUPPRESSION_MODE_PROP, e);
+                SuppressionMode::default()
+            }),
+            _ => SuppressionMode::default(),
+        };
+
+        let radius = match read(SUPPRESSION_RADIUS_PROP) {
+            Ok(Some(text)) if !text.is_empty() => text
+                .parse()
+                .ok()
+                .filter(|radius| *radius >= 0)
+                .unwrap_or_else(|| {
+                    log::error!("Ignoring {}: {:?}", SUPPRESSION_RADIUS_PROP, text);
+                    radius
+                }),
+            _ => radius,
+        };
+
+        let zones = match read(SUPPRESSION_ZONES_PROP) {
+            Ok(Some(text)) => SuppressionZone::parse_list(&text).unwrap_or_else(|e| {
+                log::error!("Ignoring {}: {:#}", SUPPRESSION_ZONES_PROP, e);
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+
+        Self {
+            mode,
+            radius,
+            zones,
+        }
+    }
+
+    #[cfg(feature = "local")]
+    pub fn from_properties(radius: i32) -> Self {
+        Self {
+            radius,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the zones active while the emulated sources touch as in `emulated`.
+    pub fn active_zones<'a>(&self, emulated: impl Iterator<Item = &'a [SlotState]>) -> Vec<Rect> {
+        let mut rects = Vec::new();
+
+        for slots in emulated {
+            if self.radius > 0 {
+                rects.extend(
+                    slots
+                        .iter()
+                        .filter(|slot| slot.is_active())
+                        .map(|slot| Rect::around(slot.x, slot.y, self.radius)),
+                );
+            }
+
+            rects.extend(
+                self.zones
+                    .iter()
+                    .filter(|zone| {
+                        slots
+                            .get(zone.slots.clone())
+                            .is_some_and(|slots| slots.iter().any(SlotState::is_active))
+                    })
+                    .map(|zone| zone.rect),
+            );
+        }
+
+        rects
+    }
+}
+
+impl Suppressor {
+    /// Returns `slots` of a real source without the contacts `zones` suppress.
+    pub fn filter(
+        &mut self,
+        mode: SuppressionMode,
+        zones: &[Rect],
+        slots: &[SlotState],
+    ) -> Vec<SlotState> {
+        // lifted fingers may touch again
+        self.dropped
+            .retain(|(slot, id)| slots.get(*slot).and_then(|s| s.tracking_id) == Some(*id));
+
+        slots
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| {
+                let Some(id) = slot.tracking_id else {
+                    return *slot;
+                };
+
+                let in_zone = zones.iter().any(|rect| rect.contains(slot.x, slot.y));
+
+                if in_zone && mode == SuppressionMode::Drop {
+                    self.dropped.insert((i, id));
+                }
+
+                if in_zone || self.dropped.contains(&(i, id)) {
+                    SlotState {
+                        tracking_id: None,
+                        ..*slot
+                    }
+                } else {
+                    *slot
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(id: i32, x: i32, y: i32) -> SlotState {
+        SlotState {
+            tracking_id: Some(id),
+            x,
+            y,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!(
+            "drop".parse::<SuppressionMode>().unwrap(),
+            SuppressionMode::Drop
+        );
+        assert_eq!(
+            "defer".parse::<SuppressionMode>().unwrap(),
+            SuppressionMode::Defer
+        );
+        assert!("hide".parse::<SuppressionMode>().is_err());
+    }
+
+    #[test]
+    fn parses_zones() {
+        let zones = SuppressionZone::parse_list(" 0:0,0,300,2400; 2:10,20,30,40;").unwrap();
+
+        assert_eq!(
+            zones,
+            vec![
+                SuppressionZone {
+                    rect: Rect {
+                        left: 0,
+                        top: 0,
+                        right: 300,
+                        bottom: 2400,
+                    },
+                    slots: 0..FINGERS_PER_SLOT,
+                },
+                SuppressionZone {
+                    rect: Rect {
+                        left: 10,
+                        top: 20,
+                        right: 30,
+                        bottom: 40,
+                    },
+                    slots: 2 * FINGERS_PER_SLOT..3 * FINGERS_PER_SLOT,
+                },
+            ]
+        );
+        assert!(SuppressionZone::parse_list("").unwrap().is_empty());
+
+        for bad in ["0,0,1,1", "3:0,0,1,1", "0:0,0,1", "0:5,0,1,1", "x:0,0,1,1"] {
+            assert!(SuppressionZone::parse_list(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn activates_zones_with_emulated_contacts() {
+        let config = SuppressionConfig {
+            radius: 10,
+            zones: vec![SuppressionZone {
+                rect: Rect {
+                    left: 0,
+                    top: 0,
+                    right: 50,
+                    bottom: 50,
+                },
+                slots: 4..8,
+            }],
+            ..Default::default()
+        };
+        let mut emulated = vec![SlotState::default(); 8];
+
+        assert!(config.active_zones([&emulated[..]].into_iter()).is_empty());
+
+        emulated[1] = contact(0, 100, 200);
+        assert_eq!(
+            config.active_zones([&emulated[..]].into_iter()),
+            vec![Rect::around(100, 200, 10)]
+        );
+
+        emulated[1].tracking_id = None;
+        emulated[5] = contact(1, 500, 500);
+        let zones = config.active_zones([&emulated[..]].into_iter());
+        assert_eq!(zones.len(), 2);
+        assert!(zones[1].contains(25, 50));
+    }
+
+    #[test]
+    fn drops_contacts_until_lifted() {
+        let mut suppressor = Suppressor::default();
+        let zones = [Rect::around(100, 100, 10)];
+
+        let mut real = vec![contact(7, 105, 95), contact(8, 300, 300)];
+        let shown = suppressor.filter(SuppressionMode::Drop, &zones, &real);
+        assert!(!shown[0].is_active());
+        assert!(shown[1].is_active());
+
+        // still dropped with the zone gone
+        let shown = suppressor.filter(SuppressionMode::Drop, &[], &real);
+        assert!(!shown[0].is_active());
+
+        real[0] = contact(9, 105, 95);
+        let shown = suppressor.filter(SuppressionMode::Drop, &[], &real);
+        assert_eq!(shown[0], real[0]);
+    }
+
+    #[test]
+    fn defers_contacts_while_zone_active() {
+        let mut suppressor = Suppressor::default();
+        let zones = [Rect::around(100, 100, 10)];
+        let real = vec![contact(7, 110, 110)];
+
+        let shown = suppressor.filter(SuppressionMode::Defer, &zones, &real);
+        assert!(!shown[0].is_active());
+        // kept so it shows where the finger is
+        assert_eq!(shown[0].x, 110);
+
+        let shown = suppressor.filter(SuppressionMode::Defer, &[], &real);
+        assert_eq!(shown, real);
+    }
+}
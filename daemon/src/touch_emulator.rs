@@ -1,20 +1,52 @@
+use crate::action::{wait_cancelled, Signal};
+use crate::display::DisplayTransform;
+use crate::utils::clock::Clock;
 use crate::utils::counter::IncrementalCounter;
 use evdev_rs::enums::{EventCode, EV_ABS, EV_KEY, EV_SYN};
 use evdev_rs::InputEvent;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::fmt::Formatter;
+use std::time::Duration;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+const MOTION_FRAME_INTERVAL: Duration = Duration::from_millis(8);
 
 pub struct TouchEmulator {
     output: Sender<InputEvent>,
     slot_states: Vec<bool>,
     touch_counter: IncrementalCounter<i32>,
+    display: DisplayTransform,
+    /// Sent with every touch if set, for panels reporting pressure.
+    pressure: Option<i32>,
+    /// Stamps the frames, the touchscreen uses the same clock.
+    clock: Clock,
 }
 
 #[derive(Debug)]
 pub enum Error {
     InvalidSlotCount,
     InvalidSlotId,
+    SlotNotTouched,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Curve {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+/// Straight path a touch travels from `from` to `to` within `duration`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MotionPath {
+    pub from: (i32, i32),
+    pub to: (i32, i32),
+    pub duration: Duration,
+    pub curve: Curve,
 }
 
 impl fmt::Display for Error {
@@ -24,14 +56,50 @@ impl fmt::Display for Error {
                 write!(f, "Invalid slot count! Min count is 1, max 20.")
             }
             Error::InvalidSlotId => write!(f, "Invalid slot id!"),
+            Error::SlotNotTouched => write!(f, "Slot is not touched!"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+impl Curve {
+    /// Maps linear progress `t` in `0.0..=1.0` onto the curve.
+    pub fn apply(&self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+
+        match self {
+            Curve::Linear => t,
+            Curve::EaseIn => t * t,
+            Curve::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Curve::EaseInOut => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - (-2.0 * t + 2.0).powi(2) / 2.0
+                }
+            }
+        }
+    }
+}
+
+impl MotionPath {
+    pub fn position_at(&self, elapsed: Duration) -> (i32, i32) {
+        let t = if self.duration.is_zero() {
+            1.0
+        } else {
+            elapsed.as_secs_f64() / self.duration.as_secs_f64()
+        };
+        let progress = self.curve.apply(t);
+
+        let lerp = |a: i32, b: i32| a + ((b - a) as f64 * progress).round() as i32;
+
+        (lerp(self.from.0, self.to.0), lerp(self.from.1, self.to.1))
+    }
+}
+
 impl TouchEmulator {
-    pub fn new(slot_count: u8) -> anyhow::Result<(Self, Receiver<InputEvent>)> {
+    pub fn new(slot_count: u8, clock: Clock) -> anyhow::Result<(Self, Receiver<InputEvent>)> {
         if slot_count == 0 || slot_count > 20 {
             return Err(Error::InvalidSlotCount.into());
         }
@@ -46,17 +114,41 @@ impl TouchEmulator {
                 output: tx,
                 slot_states,
                 touch_counter: IncrementalCounter::new(0),
+                display: DisplayTransform::default(),
+                pressure: None,
+                clock,
             },
             rx,
         ))
     }
 
+    pub fn display(&self) -> &DisplayTransform {
+        &self.display
+    }
+
+    /// Points passed to the emulator are display pixels, mapped onto the panel by this.
+    pub fn display_mut(&mut self) -> &mut DisplayTransform {
+        &mut self.display
+    }
+
+    pub fn set_pressure(&mut self, pressure: Option<i32>) {
+        self.pressure = pressure;
+    }
+
+    /// Returns the slots currently touching.
+    #[cfg_attr(feature = "local", allow(dead_code))]
+    pub fn active_slots(&self) -> Vec<usize> {
+        (0..self.slot_states.len())
+            .filter(|slot| self.slot_states[*slot])
+            .collect()
+    }
+
     async fn tap(&mut self, slot: usize, pos: Option<(i32, i32)>) -> anyhow::Result<()> {
-        if self.slot_states.len() < slot {
+        if self.slot_states.len() <= slot {
             return Err(Error::InvalidSlotId.into());
         }
 
-        let now = || std::time::SystemTime::now().try_into().unwrap();
+        let time = self.clock.now();
         let is_press = pos.is_some();
 
         if self.slot_states[slot] ^ (!is_press) {
@@ -69,7 +161,7 @@ impl TouchEmulator {
 
         self.output
             .send(InputEvent {
-                time: now(),
+                time,
                 event_code: EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT),
                 value: slot as i32,
             })
@@ -77,7 +169,7 @@ impl TouchEmulator {
 
         self.output
             .send(InputEvent {
-                time: now(),
+                time,
                 event_code: EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID),
                 value: if is_press {
                     self.touch_counter.next()
@@ -90,7 +182,7 @@ impl TouchEmulator {
         if (is_press && !touched_before) || (!is_press && !touched_after) {
             self.output
                 .send(InputEvent {
-                    time: now(),
+                    time,
                     event_code: EventCode::EV_KEY(EV_KEY::BTN_TOUCH),
                     value: is_press as i32,
                 })
@@ -98,7 +190,7 @@ impl TouchEmulator {
 
             self.output
                 .send(InputEvent {
-                    time: now(),
+                    time,
                     event_code: EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER),
                     value: is_press as i32,
                 })
@@ -106,9 +198,11 @@ impl TouchEmulator {
         }
 
         if let Some((x, y)) = pos {
+            let (x, y) = self.display.to_panel(x, y);
+
             self.output
                 .send(InputEvent {
-                    time: now(),
+                    time,
                     event_code: EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X),
                     value: x,
                 })
@@ -116,16 +210,26 @@ impl TouchEmulator {
 
             self.output
                 .send(InputEvent {
-                    time: now(),
+                    time,
                     event_code: EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y),
                     value: y,
                 })
                 .await?;
+
+            if let Some(pressure) = self.pressure {
+                self.output
+                    .send(InputEvent {
+                        time,
+                        event_code: EventCode::EV_ABS(EV_ABS::ABS_MT_PRESSURE),
+                        value: pressure,
+                    })
+                    .await?;
+            }
         }
 
         self.output
             .send(InputEvent {
-                time: now(),
+                time,
                 event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
                 value: 0,
             })
@@ -141,4 +245,133 @@ impl TouchEmulator {
     pub async fn stop_tap(&mut self, slot: usize) -> anyhow::Result<()> {
         self.tap(slot, None).await
     }
+
+    pub async fn move_tap(&mut self, slot: usize, x: i32, y: i32) -> anyhow::Result<()> {
+        match self.slot_states.get(slot) {
+            None => return Err(Error::InvalidSlotId.into()),
+            Some(false) => return Err(Error::SlotNotTouched.into()),
+            Some(true) => {}
+        }
+
+        let time = self.clock.now();
+        let (x, y) = self.display.to_panel(x, y);
+
+        for (event_code, value) in [
+            (EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), slot as i32),
+            (EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), x),
+            (EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), y),
+            (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ] {
+            self.output
+                .send(InputEvent {
+                    time,
+                    event_code,
+                    value,
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Touches `slot` at the start of `path` and moves it along the path.
+///
+/// The touch is left down at the end of the path, or where it was once `signal` is cancelled.
+pub async fn animate(
+    emulator: &Mutex<TouchEmulator>,
+    slot: usize,
+    path: &MotionPath,
+    signal: &mut watch::Receiver<Signal>,
+) -> anyhow::Result<()> {
+    let (x, y) = path.from;
+    emulator.lock().await.start_tap(slot, x, y).await?;
+
+    let start = Instant::now();
+    let mut frames = interval(MOTION_FRAME_INTERVAL);
+    frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    frames.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = frames.tick() => {}
+            _ = wait_cancelled(signal) => break,
+        }
+
+        let elapsed = start.elapsed();
+        let (x, y) = path.position_at(elapsed);
+        emulator.lock().await.move_tap(slot, x, y).await?;
+
+        if elapsed >= path.duration {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [Curve; 4] = [
+        Curve::Linear,
+        Curve::EaseIn,
+        Curve::EaseOut,
+        Curve::EaseInOut,
+    ];
+
+    #[test]
+    fn curves_keep_endpoints() {
+        for curve in CURVES {
+            assert_eq!(curve.apply(0.0), 0.0, "{:?}", curve);
+            assert_eq!(curve.apply(1.0), 1.0, "{:?}", curve);
+            // progress outside the path is clamped
+            assert_eq!(curve.apply(-0.5), 0.0, "{:?}", curve);
+            assert_eq!(curve.apply(1.5), 1.0, "{:?}", curve);
+        }
+
+        assert_eq!(Curve::EaseInOut.apply(0.5), 0.5);
+    }
+
+    #[test]
+    fn curves_are_monotonic() {
+        for curve in CURVES {
+            let mut last = 0.0;
+
+            for i in 1..=100 {
+                let progress = curve.apply(i as f64 / 100.0);
+                assert!(progress >= last, "{:?} goes back at {}", curve, i);
+                last = progress;
+            }
+        }
+    }
+
+    #[test]
+    fn moves_along_path() {
+        let path = MotionPath {
+            from: (100, 1000),
+            to: (300, 200),
+            duration: Duration::from_millis(100),
+            curve: Curve::Linear,
+        };
+
+        assert_eq!(path.position_at(Duration::ZERO), (100, 1000));
+        assert_eq!(path.position_at(Duration::from_millis(50)), (200, 600));
+        assert_eq!(path.position_at(Duration::from_millis(100)), (300, 200));
+        // stays at the end once the path is done
+        assert_eq!(path.position_at(Duration::from_millis(250)), (300, 200));
+    }
+
+    #[test]
+    fn zero_duration_path_ends_right_away() {
+        let path = MotionPath {
+            from: (100, 100),
+            to: (500, 900),
+            duration: Duration::ZERO,
+            curve: Curve::EaseIn,
+        };
+
+        assert_eq!(path.position_at(Duration::ZERO), (500, 900));
+    }
 }
@@ -1,50 +1,133 @@
-use crate::utils::counter::IncrementalCounter;
+use crate::fts::TouchCapabilities;
+use crate::status::{MergerStatus, SharedStatus, SourceStatus};
+use crate::suppression::{Rect, SuppressionConfig, Suppressor};
+use crate::touch_state::{finish_frame, FrameError, MergedSlots, SlotState, SourceSlots};
+use crate::utils::clock::Clock;
 use anyhow::Context;
-use evdev_rs::enums::{BusType, EventCode, EventType, InputProp, EV_ABS, EV_KEY, EV_SYN};
-use evdev_rs::{AbsInfo, DeviceWrapper, EnableCodeData, InputEvent, UInputDevice, UninitDevice};
+use evdev_rs::enums::{BusType, EventCode, EventType, InputProp, EV_ABS, EV_KEY, EV_MSC, EV_SYN};
+use evdev_rs::{
+    AbsInfo, DeviceWrapper, EnableCodeData, InputEvent, TimeVal, UInputDevice, UninitDevice,
+};
+use futures::stream::{self, Stream};
 use futures::StreamExt;
-use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::pin::Pin;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamMap;
 
+/// Slots of the merged device, Android's InputReader handles up to 32.
+pub const MERGED_SLOT_COUNT: i32 = 32;
+/// Largest `ABS_MT_TRACKING_ID` of the merged device, ids wrap around after it.
+const MAX_TRACKING_ID: i32 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceKind {
+    /// A real panel, its contacts are suppressed around emulated ones.
+    Touchscreen,
+    Emulated,
+}
+
 pub struct TouchSourceDeclaration {
     pub slot_count: i32,
+    pub kind: SourceKind,
 }
 
+/// Identifies an attached source, ids aren't reused so a late detach can't hit a newer source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceId(usize);
+
 pub struct TouchSourceState {
     pub event_buffer: Vec<InputEvent>,
-    pub current_slot: i32,
-    pub in_touch: bool,
+    /// Contacts as of the last frame the merger took.
+    pub slots: SourceSlots,
+    /// Events were dropped, the next frame brings the source back in sync.
+    pub resyncing: bool,
+    /// Contacts as the merged device shows them, without the suppressed ones.
+    pub shown: Vec<SlotState>,
+    pub suppressor: Suppressor,
 }
 
-pub struct TouchMerger {
-    idev_decls: Box<[TouchSourceDeclaration]>,
-    idev_states: Box<[RefCell<TouchSourceState>]>,
+struct TouchSource {
+    decl: TouchSourceDeclaration,
+    /// First merged slot of the source, its slots follow.
+    first_slot: i32,
+    state: TouchSourceState,
+}
+
+/// Ends with `None` once the source closes.
+type SourceStream = Pin<Box<dyn Stream<Item = Option<InputEvent>> + Send>>;
 
-    output_device: UInputDevice,
-    stream_map: StreamMap<usize, ReceiverStream<InputEvent>>,
-    current_slot: i32,
-    tracking_id: IncrementalCounter<i32>,
+enum Command {
+    Attach {
+        decl: TouchSourceDeclaration,
+        events: Receiver<InputEvent>,
+        reply: oneshot::Sender<anyhow::Result<SourceId>>,
+    },
+}
+
+/// Attaches sources to a running merger, closing a source's sender detaches it.
+#[derive(Clone)]
+pub struct TouchMergerHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+/// Keeps `MSC_TIMESTAMP` on the panel's timeline for the frames the panel didn't stamp.
+#[derive(Debug, Default)]
+struct MscTimestamps {
+    /// Last `MSC_TIMESTAMP` of the panel and the time of its frame.
+    last: Option<(i32, TimeVal)>,
+}
+
+/// The merged device and what it shows.
+struct MergedOutput {
+    device: UInputDevice,
+    slots: MergedSlots,
+    /// Set if the panel reports `MSC_TIMESTAMP`.
+    timestamps: Option<MscTimestamps>,
+}
+
+pub struct TouchMerger {
+    sources: BTreeMap<SourceId, TouchSource>,
+    next_source_id: usize,
+
+    output: MergedOutput,
+    stream_map: StreamMap<SourceId, SourceStream>,
+    commands: mpsc::Receiver<Command>,
+    suppression: SuppressionConfig,
+    /// Stamps the frames no source event is behind.
+    clock: Clock,
+    status: SharedStatus,
 }
 
 impl TouchSourceDeclaration {
-    pub fn new(slot_count: i32) -> Self {
-        Self { slot_count }
+    pub fn new(slot_count: i32, kind: SourceKind) -> Self {
+        Self { slot_count, kind }
     }
 }
 
 impl TouchSourceState {
-    pub fn new() -> Self {
+    pub fn new(slot_count: i32) -> Self {
         Self {
-            in_touch: false,
-            current_slot: 0,
             event_buffer: Vec::new(),
+            slots: SourceSlots::new(slot_count as usize),
+            resyncing: false,
+            shown: vec![SlotState::default(); slot_count as usize],
+            suppressor: Suppressor::default(),
         }
     }
 
     pub fn try_get_complete_event(&mut self, event: InputEvent) -> Option<Box<[InputEvent]>> {
         let code = event.event_code;
+
+        // the frame in the buffer lost some of its events
+        if code == EventCode::EV_SYN(EV_SYN::SYN_DROPPED) {
+            self.event_buffer.clear();
+            self.resyncing = true;
+            return None;
+        }
+
         self.event_buffer.push(event);
 
         if code == EventCode::EV_SYN(EV_SYN::SYN_REPORT) {
@@ -54,12 +137,149 @@ impl TouchSourceState {
 
         None
     }
+
+    /// Applies a complete frame to the slots, returning the events that aren't about contacts.
+    ///
+    /// Nothing is known about the contacts if the frame after `SYN_DROPPED` can't be applied,
+    /// they are all lifted rather than left stuck.
+    pub fn apply_frame(&mut self, frame: &[InputEvent]) -> Result<Vec<InputEvent>, FrameError> {
+        let resyncing = std::mem::take(&mut self.resyncing);
+
+        match self.slots.apply(frame) {
+            Err(e) if resyncing => {
+                log::warn!("Lifting all contacts, resync failed: {}", e);
+                self.slots.lift_all();
+                Ok(Vec::new())
+            }
+            res => res,
+        }
+    }
+}
+
+impl MscTimestamps {
+    /// Returns the `MSC_TIMESTAMP` of a frame at `time`, unless the panel stamped `events`.
+    fn stamp(&mut self, events: &[InputEvent], time: TimeVal) -> Option<i32> {
+        if let Some(event) = events
+            .iter()
+            .find(|event| event.event_code == EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP))
+        {
+            self.last = Some((event.value, time));
+            return None;
+        }
+
+        // time_t and suseconds_t are 32 bits wide on 32 bit targets
+        #[allow(clippy::useless_conversion)]
+        let micros = |time: TimeVal| i64::from(time.tv_sec) * 1_000_000 + i64::from(time.tv_usec);
+
+        // the panel's counter wraps around, so does this one
+        Some(match self.last {
+            Some((value, last)) => value.wrapping_add((micros(time) - micros(last)) as i32),
+            None => micros(time) as i32,
+        })
+    }
+}
+
+impl MergedOutput {
+    /// Writes the frame taking the slots of a source from `old` to `new`, followed by `others`.
+    ///
+    /// uinput stamps events itself when they are written, `MSC_TIMESTAMP` keeps `time` for
+    /// panels reporting it.
+    fn write_frame(
+        &mut self,
+        id: SourceId,
+        first_slot: i32,
+        old: &[SlotState],
+        new: &[SlotState],
+        others: Vec<InputEvent>,
+        time: TimeVal,
+    ) -> anyhow::Result<()> {
+        let mut events = self.slots.update(id.0, first_slot as usize, old, new, time);
+
+        events.extend(others.into_iter().map(|event| InputEvent { time, ..event }));
+
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(value) = self
+            .timestamps
+            .as_mut()
+            .and_then(|timestamps| timestamps.stamp(&events, time))
+        {
+            events.push(InputEvent {
+                time,
+                event_code: EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP),
+                value,
+            });
+        }
+
+        finish_frame(&mut events, time);
+
+        for event in &events {
+            self.device
+                .write_event(event)
+                .context("Failed to write to output device")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the first of `slot_count` merged slots that no range of `used` overlaps.
+fn free_first_slot(
+    used: impl Iterator<Item = (i32, i32)>,
+    slot_count: i32,
+    capacity: i32,
+) -> Option<i32> {
+    let mut used: Vec<(i32, i32)> = used.collect();
+    used.sort_unstable();
+
+    let mut first = 0;
+
+    for (start, count) in used {
+        if start - first >= slot_count {
+            return Some(first);
+        }
+
+        first = first.max(start + count);
+    }
+
+    (capacity - first >= slot_count).then_some(first)
+}
+
+impl TouchMergerHandle {
+    /// Adds a source, it gets the first free range of merged slots.
+    ///
+    /// Dropping the sender of `events` detaches the source, lifting its touches and freeing
+    /// its slots.
+    pub async fn attach(
+        &self,
+        decl: TouchSourceDeclaration,
+        events: Receiver<InputEvent>,
+    ) -> anyhow::Result<SourceId> {
+        let (reply, result) = oneshot::channel();
+
+        self.commands
+            .send(Command::Attach {
+                decl,
+                events,
+                reply,
+            })
+            .await
+            .context("Touch merger is gone")?;
+
+        result.await.context("Touch merger is gone")?
+    }
 }
 
 impl TouchMerger {
-    fn create_input_device(slot_count: i32) -> anyhow::Result<UInputDevice> {
-        if slot_count <= 0 || slot_count > 20 {
-            return Err(anyhow::Error::msg("slot count > 20 or <= 0"));
+    fn create_input_device(
+        slot_count: i32,
+        capabilities: &TouchCapabilities,
+    ) -> anyhow::Result<UInputDevice> {
+        // Android's InputReader handles up to 32 slots
+        if slot_count <= 0 || slot_count > 32 {
+            return Err(anyhow::Error::msg("slot count > 32 or <= 0"));
         }
 
         let u = UninitDevice::new().unwrap();
@@ -79,6 +299,24 @@ impl TouchMerger {
         u.enable(EventCode::EV_KEY(EV_KEY::BTN_TOUCH))?;
         u.enable(EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER))?;
 
+        for prop in &capabilities.properties {
+            u.enable_property(prop)?;
+        }
+
+        // the touchscreen filtered its events already, filtering them again would drop small
+        // moves
+        for (code, info) in &capabilities.codes {
+            let data = info.map(|info| {
+                EnableCodeData::AbsInfo(AbsInfo {
+                    value: 0,
+                    fuzz: 0,
+                    ..info
+                })
+            });
+
+            u.enable_event_code(code, data)?;
+        }
+
         let abs = |min: i32, max: i32| {
             EnableCodeData::AbsInfo(AbsInfo {
                 value: 0,
@@ -90,154 +328,389 @@ impl TouchMerger {
             })
         };
 
+        // slots and tracking ids are the merger's own
         u.enable_event_code(
             &EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT),
             Some(abs(0, slot_count - 1)),
         )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_TOUCH_MAJOR),
-            Some(abs(0, 10800)),
-        )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_TOUCH_MINOR),
-            Some(abs(0, 24000)),
-        )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_WIDTH_MAJOR),
-            Some(abs(0, 127)),
-        )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_WIDTH_MINOR),
-            Some(abs(0, 127)),
-        )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_ORIENTATION),
-            Some(abs(-90, 90)),
-        )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X),
-            Some(abs(0, 10799)),
-        )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y),
-            Some(abs(0, 23999)),
-        )?;
         u.enable_event_code(
             &EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID),
-            Some(abs(0, 65535)),
-        )?;
-        u.enable_event_code(
-            &EventCode::EV_ABS(EV_ABS::ABS_MT_DISTANCE),
-            Some(abs(0, 127)),
+            Some(abs(0, MAX_TRACKING_ID)),
         )?;
 
         UInputDevice::create_from_device(&u).context("Failed to create UInputDevice from Device")
     }
     pub fn new(
-        sources: Box<[(TouchSourceDeclaration, Receiver<InputEvent>)]>,
-    ) -> anyhow::Result<Self> {
-        let mut stream_map = StreamMap::<usize, _>::new();
-
-        let decls: Box<[TouchSourceDeclaration]> = Vec::from(sources)
-            .into_iter()
-            .enumerate()
-            .map(|(i, (d, s))| {
-                stream_map.insert(i, ReceiverStream::new(s));
-                d
-            })
-            .collect();
-
-        let output_device = Self::create_input_device(decls.iter().map(|d| d.slot_count).sum())
+        capabilities: &TouchCapabilities,
+        suppression: SuppressionConfig,
+        clock: Clock,
+        status: SharedStatus,
+    ) -> anyhow::Result<(Self, TouchMergerHandle)> {
+        let device = Self::create_input_device(MERGED_SLOT_COUNT, capabilities)
             .context("Failed to create input device for TouchMerger")?;
-
-        Ok(Self {
-            idev_states: decls
-                .iter()
-                .map(|_| RefCell::new(TouchSourceState::new()))
-                .collect(),
-            idev_decls: decls,
-            output_device,
-            stream_map,
-            current_slot: 0,
-            tracking_id: IncrementalCounter::new(0),
-        })
+        let has_timestamps = capabilities
+            .codes
+            .iter()
+            .any(|(code, _)| *code == EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP));
+        let (commands_tx, commands) = mpsc::channel(4);
+
+        Ok((
+            Self {
+                sources: BTreeMap::new(),
+                next_source_id: 0,
+                output: MergedOutput {
+                    device,
+                    slots: MergedSlots::new(MERGED_SLOT_COUNT as usize, MAX_TRACKING_ID),
+                    timestamps: has_timestamps.then(MscTimestamps::default),
+                },
+                stream_map: StreamMap::new(),
+                commands,
+                suppression,
+                clock,
+                status,
+            },
+            TouchMergerHandle {
+                commands: commands_tx,
+            },
+        ))
     }
 
-    fn any_touched_except(&self, index: usize) -> bool {
-        self.idev_states
-            .iter()
-            .enumerate()
-            .any(|(i, d)| i != index && d.borrow().in_touch)
+    /// Returns the node of the merged uinput device.
+    pub fn devnode(&self) -> Option<String> {
+        self.output.device.devnode().map(str::to_string)
     }
 
-    fn get_slot_with_offset(&self, index: usize, slot: i32) -> i32 {
-        let mut slot = slot;
+    fn publish_status(&self) {
+        self.status.write().unwrap().merger = MergerStatus {
+            sources: self
+                .sources
+                .iter()
+                .map(|(id, source)| SourceStatus {
+                    id: id.0,
+                    first_slot: source.first_slot,
+                    slot_count: source.decl.slot_count,
+                    current_slot: source.first_slot + source.state.slots.current_slot() as i32,
+                    in_touch: source.state.slots.is_touching(),
+                    buffered: source.state.event_buffer.len(),
+                })
+                .collect(),
+            contacts: self
+                .output
+                .slots
+                .slots()
+                .iter()
+                .enumerate()
+                .filter(|(_, slot)| slot.is_active())
+                .map(|(i, slot)| (i as i32, *slot))
+                .collect(),
+            current_slot: self.output.slots.current_slot(),
+            next_tracking_id: self.output.slots.next_tracking_id(),
+        };
+    }
 
-        for i in 0..index {
-            slot += self.idev_decls[i].slot_count;
+    fn attach(
+        &mut self,
+        decl: TouchSourceDeclaration,
+        events: Receiver<InputEvent>,
+    ) -> anyhow::Result<SourceId> {
+        if decl.slot_count <= 0 {
+            return Err(anyhow::Error::msg("slot count <= 0"));
         }
 
-        slot
+        let first_slot = free_first_slot(
+            self.sources
+                .values()
+                .map(|source| (source.first_slot, source.decl.slot_count)),
+            decl.slot_count,
+            MERGED_SLOT_COUNT,
+        )
+        .with_context(|| format!("No {} free slots for touch source", decl.slot_count))?;
+
+        let id = SourceId(self.next_source_id);
+        self.next_source_id += 1;
+
+        let events: SourceStream = ReceiverStream::new(events)
+            .map(Some)
+            .chain(stream::once(async { None }))
+            .boxed();
+        self.stream_map.insert(id, events);
+
+        log::info!(
+            "Touch source {} attached to slots {}..{}",
+            id.0,
+            first_slot,
+            first_slot + decl.slot_count
+        );
+        self.sources.insert(
+            id,
+            TouchSource {
+                state: TouchSourceState::new(decl.slot_count),
+                decl,
+                first_slot,
+            },
+        );
+        self.publish_status();
+
+        Ok(id)
     }
 
-    pub async fn processing_task(mut self) -> anyhow::Result<()> {
-        while let Some((key, val)) = self.stream_map.next().await {
-            let mut state = self.idev_states[key].borrow_mut();
+    /// Removes a closed source, lifting its touches.
+    fn detach(&mut self, id: SourceId) -> anyhow::Result<()> {
+        let source = self
+            .sources
+            .get_mut(&id)
+            .with_context(|| format!("No touch source {}", id.0))?;
+        self.stream_map.remove(&id);
 
-            let Some(events) = state.try_get_complete_event(val) else {
-                continue;
-            };
+        source.state.slots.lift_all();
+        self.show(id, Vec::new(), self.clock.now())?;
+        self.sources.remove(&id);
 
-            let mut new_events = Vec::<InputEvent>::new();
+        log::info!("Touch source {} detached", id.0);
+        self.publish_status();
 
-            for mut event in events {
-                match &event.event_code {
-                    EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT) => {
-                        let slot = self.get_slot_with_offset(key, event.value);
+        Ok(())
+    }
 
-                        state.current_slot = slot;
-                        self.current_slot = slot;
-                        event.value = slot;
-                    }
-                    EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID) => {
-                        if event.value != 0xFFFFFFFFu32 as i32 {
-                            event.value = self.tracking_id.next();
-                        }
-                    }
+    /// Returns the suppression zones of the emulated contacts touching right now.
+    fn active_zones(&self) -> Vec<Rect> {
+        self.suppression.active_zones(
+            self.sources
+                .values()
+                .filter(|source| source.decl.kind == SourceKind::Emulated)
+                .map(|source| source.state.slots.slots()),
+        )
+    }
 
-                    EventCode::EV_KEY(EV_KEY::BTN_TOUCH)
-                    | EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER) => {
-                        state.in_touch = event.value == 1;
+    /// Shows the contacts of a source on the merged device at `time`, followed by `others`.
+    ///
+    /// Emulated contacts move the suppression zones, so the touchscreens are shown again before
+    /// them, a game never sees a suppressed finger next to the touch that suppresses it.
+    fn show(
+        &mut self,
+        id: SourceId,
+        mut others: Vec<InputEvent>,
+        time: TimeVal,
+    ) -> anyhow::Result<()> {
+        let zones = self.active_zones();
+        let kind = self.sources[&id].decl.kind;
+
+        let ids: Vec<SourceId> = match kind {
+            SourceKind::Touchscreen => vec![id],
+            SourceKind::Emulated => self
+                .sources
+                .iter()
+                .filter(|(_, source)| source.decl.kind == SourceKind::Touchscreen)
+                .map(|(id, _)| *id)
+                .chain(std::iter::once(id))
+                .collect(),
+        };
 
-                        if self.any_touched_except(key) {
-                            continue;
-                        }
-                    }
+        for shown_id in ids {
+            let source = self.sources.get_mut(&shown_id).unwrap();
+            let state = &mut source.state;
 
-                    _ => {}
+            let shown = match source.decl.kind {
+                SourceKind::Touchscreen => {
+                    state
+                        .suppressor
+                        .filter(self.suppression.mode, &zones, state.slots.slots())
                 }
+                SourceKind::Emulated => state.slots.slots().to_vec(),
+            };
 
-                if self.current_slot != state.current_slot {
-                    new_events.push(InputEvent {
-                        time: std::time::SystemTime::now().try_into()?,
-                        event_code: EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT),
-                        value: state.current_slot,
-                    });
+            self.output.write_frame(
+                shown_id,
+                source.first_slot,
+                &state.shown,
+                &shown,
+                if shown_id == id {
+                    std::mem::take(&mut others)
+                } else {
+                    Vec::new()
+                },
+                time,
+            )?;
+
+            state.shown = shown;
+        }
 
-                    self.current_slot = state.current_slot;
-                }
+        Ok(())
+    }
+
+    fn on_command(&mut self, command: Command) {
+        // the caller may have given up waiting
+        match command {
+            Command::Attach {
+                decl,
+                events,
+                reply,
+            } => {
+                let _ = reply.send(self.attach(decl, events));
+            }
+        }
+    }
+
+    fn on_event(&mut self, key: SourceId, val: InputEvent) -> anyhow::Result<()> {
+        let Some(source) = self.sources.get_mut(&key) else {
+            return Ok(());
+        };
+
+        let Some(frame) = source.state.try_get_complete_event(val) else {
+            return Ok(());
+        };
 
-                event.time = std::time::SystemTime::now().try_into()?;
-                new_events.push(event);
+        let others = match source.state.apply_frame(&frame) {
+            Ok(others) => others,
+            Err(e) => {
+                log::warn!("Dropping frame of touch source {}: {}", key.0, e);
+                return Ok(());
             }
+        };
+
+        // frames end with SYN_REPORT, stamped like the rest of the frame by the source
+        let time = frame.last().unwrap().time;
+        self.show(key, others, time)?;
+        self.publish_status();
 
-            for event in &new_events {
-                self.output_device
-                    .write_event(event)
-                    .context("Failed to write to output device")?;
+        Ok(())
+    }
+
+    /// Merges the sources until all of them closed and every handle is dropped.
+    pub async fn processing_task(mut self) -> anyhow::Result<()> {
+        let mut handles_dropped = false;
+
+        loop {
+            tokio::select! {
+                Some((key, val)) = self.stream_map.next(), if !self.stream_map.is_empty() => {
+                    match val {
+                        Some(val) => self.on_event(key, val)?,
+                        None => {
+                            log::warn!("Touch source {} closed", key.0);
+                            self.detach(key)?;
+                        }
+                    }
+                }
+                command = self.commands.recv(), if !handles_dropped => {
+                    match command {
+                        Some(command) => self.on_command(command),
+                        None => handles_dropped = true,
+                    }
+                }
+                else => break,
             }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(event_code: EventCode, value: i32) -> InputEvent {
+        InputEvent {
+            time: evdev_rs::TimeVal::new(0, 0),
+            event_code,
+            value,
+        }
+    }
+
+    fn feed(state: &mut TouchSourceState, events: &[InputEvent]) -> Option<Box<[InputEvent]>> {
+        events
+            .iter()
+            .filter_map(|event| state.try_get_complete_event(event.clone()))
+            .last()
+    }
+
+    fn touch_down(slot: i32, id: i32) -> Vec<InputEvent> {
+        vec![
+            ev(EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), slot),
+            ev(EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), id),
+            ev(EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 1),
+            ev(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ]
+    }
+
+    #[test]
+    fn resyncs_after_dropped_events() {
+        let mut state = TouchSourceState::new(4);
+        let frame = feed(&mut state, &touch_down(0, 1)).unwrap();
+        state.apply_frame(&frame).unwrap();
+
+        // cut short by the kernel, then the delta libevdev synced
+        let frame = feed(
+            &mut state,
+            &[
+                ev(EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 1),
+                ev(EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 2),
+                ev(EventCode::EV_SYN(EV_SYN::SYN_DROPPED), 0),
+                ev(EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 0),
+                ev(EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1),
+                ev(EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 0),
+                ev(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+            ],
+        )
+        .unwrap();
+        state.apply_frame(&frame).unwrap();
+
+        assert!(!state.slots.is_touching());
+        assert!(!state.resyncing);
+    }
+
+    #[test]
+    fn lifts_contacts_when_resync_fails() {
+        let mut state = TouchSourceState::new(4);
+        let frame = feed(&mut state, &touch_down(0, 1)).unwrap();
+        state.apply_frame(&frame).unwrap();
+
+        let bad_frame = [
+            ev(EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 9),
+            ev(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ];
+
+        // dropped as usual while in sync
+        assert!(state.apply_frame(&bad_frame).is_err());
+        assert!(state.slots.is_touching());
+
+        feed(&mut state, &[ev(EventCode::EV_SYN(EV_SYN::SYN_DROPPED), 0)]);
+        assert_eq!(state.apply_frame(&bad_frame), Ok(Vec::new()));
+        assert!(!state.slots.is_touching());
+    }
+
+    #[test]
+    fn stamps_frames_on_panel_timeline() {
+        let mut timestamps = MscTimestamps::default();
+        let time = |usec| evdev_rs::TimeVal::new(100, usec);
+
+        // nothing from the panel yet
+        assert_eq!(timestamps.stamp(&[], time(5)), Some(100_000_005));
+
+        let stamped = [ev(EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), i32::MAX - 10)];
+        assert_eq!(timestamps.stamp(&stamped, time(1000)), None);
+
+        assert_eq!(timestamps.stamp(&[], time(1004)), Some(i32::MAX - 6));
+        assert_eq!(timestamps.stamp(&[], time(1020)), Some(i32::MIN + 9));
+    }
+
+    #[test]
+    fn finds_free_slots() {
+        assert_eq!(free_first_slot([].into_iter(), 10, 32), Some(0));
+        assert_eq!(free_first_slot([(0, 10)].into_iter(), 12, 32), Some(10));
+        // the range of a detached source is reused
+        assert_eq!(free_first_slot([(10, 12)].into_iter(), 10, 32), Some(0));
+        assert_eq!(
+            free_first_slot([(10, 12), (0, 5)].into_iter(), 5, 32),
+            Some(5)
+        );
+        assert_eq!(
+            free_first_slot([(10, 12), (0, 5)].into_iter(), 6, 32),
+            Some(22)
+        );
+        assert_eq!(
+            free_first_slot([(0, 10), (10, 12)].into_iter(), 11, 32),
+            None
+        );
+    }
+}
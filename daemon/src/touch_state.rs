@@ -0,0 +1,686 @@
+use evdev_rs::enums::{EventCode, EV_ABS, EV_KEY, EV_SYN};
+use evdev_rs::{InputEvent, TimeVal};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Axes of a contact, in the order they are written.
+const CONTACT_AXES: [EV_ABS; 9] = [
+    EV_ABS::ABS_MT_POSITION_X,
+    EV_ABS::ABS_MT_POSITION_Y,
+    EV_ABS::ABS_MT_TOUCH_MAJOR,
+    EV_ABS::ABS_MT_TOUCH_MINOR,
+    EV_ABS::ABS_MT_WIDTH_MAJOR,
+    EV_ABS::ABS_MT_WIDTH_MINOR,
+    EV_ABS::ABS_MT_ORIENTATION,
+    EV_ABS::ABS_MT_PRESSURE,
+    EV_ABS::ABS_MT_TOOL_TYPE,
+];
+
+/// Contact in one multitouch slot.
+///
+/// The axes keep their values once the contact is gone, as in the kernel, a new contact
+/// only reports the axes that changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SlotState {
+    /// `None` while nothing touches the slot.
+    pub tracking_id: Option<i32>,
+    pub x: i32,
+    pub y: i32,
+    pub touch_major: i32,
+    pub touch_minor: i32,
+    pub width_major: i32,
+    pub width_minor: i32,
+    pub orientation: i32,
+    pub pressure: i32,
+    /// `MT_TOOL_*` of the contact.
+    pub tool_type: i32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    InvalidSlot(i32),
+    /// `BTN_TOUCH` doesn't match the contacts the frame leaves.
+    TouchMismatch,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::InvalidSlot(slot) => write!(f, "Invalid slot {}!", slot),
+            FrameError::TouchMismatch => write!(f, "BTN_TOUCH contradicts the contacts!"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Slots of one source as it reports them.
+#[derive(Debug, Clone)]
+pub struct SourceSlots {
+    slots: Vec<SlotState>,
+    current: usize,
+}
+
+/// Contact as a source reported it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContactKey {
+    pub source: usize,
+    pub slot: usize,
+    pub tracking_id: i32,
+}
+
+/// Tracking ids of the merged device, one per contact for as long as it lasts.
+///
+/// Ids wrap around within `0..=max`, skipping those still taken by a contact.
+pub struct TrackingIds {
+    max: i32,
+    next: i32,
+    contacts: HashMap<ContactKey, i32>,
+}
+
+/// Slots of the merged device, each source owning a range of them.
+pub struct MergedSlots {
+    slots: Vec<SlotState>,
+    current: i32,
+    tracking_ids: TrackingIds,
+}
+
+impl SlotState {
+    pub fn is_active(&self) -> bool {
+        self.tracking_id.is_some()
+    }
+
+    fn axis(&self, axis: EV_ABS) -> Option<i32> {
+        let mut slot = *self;
+
+        slot.axis_mut(axis).copied()
+    }
+
+    fn axis_mut(&mut self, axis: EV_ABS) -> Option<&mut i32> {
+        match axis {
+            EV_ABS::ABS_MT_POSITION_X => Some(&mut self.x),
+            EV_ABS::ABS_MT_POSITION_Y => Some(&mut self.y),
+            EV_ABS::ABS_MT_TOUCH_MAJOR => Some(&mut self.touch_major),
+            EV_ABS::ABS_MT_TOUCH_MINOR => Some(&mut self.touch_minor),
+            EV_ABS::ABS_MT_WIDTH_MAJOR => Some(&mut self.width_major),
+            EV_ABS::ABS_MT_WIDTH_MINOR => Some(&mut self.width_minor),
+            EV_ABS::ABS_MT_ORIENTATION => Some(&mut self.orientation),
+            EV_ABS::ABS_MT_PRESSURE => Some(&mut self.pressure),
+            EV_ABS::ABS_MT_TOOL_TYPE => Some(&mut self.tool_type),
+            _ => None,
+        }
+    }
+}
+
+impl SourceSlots {
+    pub fn new(slot_count: usize) -> Self {
+        Self {
+            slots: vec![SlotState::default(); slot_count],
+            current: 0,
+        }
+    }
+
+    pub fn slots(&self) -> &[SlotState] {
+        &self.slots
+    }
+
+    pub fn current_slot(&self) -> usize {
+        self.current
+    }
+
+    pub fn is_touching(&self) -> bool {
+        self.slots.iter().any(SlotState::is_active)
+    }
+
+    /// Applies a frame up to its `SYN_REPORT`, returning the events that aren't about
+    /// contacts.
+    ///
+    /// A frame contradicting itself is rejected as a whole and leaves the slots as they were.
+    /// Buttons of the touch and single touch axes are left out, the merged device reports
+    /// its own.
+    pub fn apply(&mut self, frame: &[InputEvent]) -> Result<Vec<InputEvent>, FrameError> {
+        let mut slots = self.slots.clone();
+        let mut current = self.current;
+        let mut touch = None;
+        let mut others = Vec::new();
+
+        for event in frame {
+            match event.event_code {
+                EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT) => {
+                    current = usize::try_from(event.value)
+                        .ok()
+                        .filter(|slot| *slot < slots.len())
+                        .ok_or(FrameError::InvalidSlot(event.value))?;
+                }
+                EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID) => {
+                    slots[current].tracking_id = (event.value >= 0).then_some(event.value);
+                }
+                EventCode::EV_ABS(axis) => {
+                    // unknown multitouch axes are dropped along with the single touch ones
+                    if let Some(value) = slots[current].axis_mut(axis) {
+                        *value = event.value;
+                    }
+                }
+                EventCode::EV_KEY(EV_KEY::BTN_TOUCH) => touch = Some(event.value != 0),
+                EventCode::EV_KEY(
+                    EV_KEY::BTN_TOOL_FINGER
+                    | EV_KEY::BTN_TOOL_DOUBLETAP
+                    | EV_KEY::BTN_TOOL_TRIPLETAP
+                    | EV_KEY::BTN_TOOL_QUADTAP
+                    | EV_KEY::BTN_TOOL_QUINTTAP,
+                ) => {}
+                EventCode::EV_SYN(_) => {}
+                _ => others.push(event.clone()),
+            }
+        }
+
+        if touch.is_some_and(|touch| touch != slots.iter().any(SlotState::is_active)) {
+            return Err(FrameError::TouchMismatch);
+        }
+
+        self.slots = slots;
+        self.current = current;
+
+        Ok(others)
+    }
+
+    /// Ends every contact, as if the source lifted all of them.
+    pub fn lift_all(&mut self) {
+        for slot in &mut self.slots {
+            slot.tracking_id = None;
+        }
+    }
+}
+
+impl TrackingIds {
+    pub fn new(max: i32) -> Self {
+        Self {
+            max,
+            next: 0,
+            contacts: HashMap::new(),
+        }
+    }
+
+    /// Returns the id the next new contact gets, unless that one is still taken.
+    pub fn peek(&self) -> i32 {
+        self.next
+    }
+
+    /// Returns the id of the contact, taking the next free one for a new contact.
+    pub fn get_or_assign(&mut self, key: ContactKey) -> i32 {
+        if let Some(id) = self.contacts.get(&key) {
+            return *id;
+        }
+
+        // there are fewer contacts than ids, so one is free
+        let mut id = self.next;
+        while self.contacts.values().any(|taken| *taken == id) {
+            id = if id >= self.max { 0 } else { id + 1 };
+        }
+
+        self.next = if id >= self.max { 0 } else { id + 1 };
+        self.contacts.insert(key, id);
+
+        id
+    }
+
+    /// Frees the id of a contact that ended.
+    pub fn release(&mut self, key: ContactKey) -> Option<i32> {
+        self.contacts.remove(&key)
+    }
+}
+
+impl MergedSlots {
+    pub fn new(slot_count: usize, max_tracking_id: i32) -> Self {
+        Self {
+            slots: vec![SlotState::default(); slot_count],
+            current: 0,
+            tracking_ids: TrackingIds::new(max_tracking_id),
+        }
+    }
+
+    pub fn slots(&self) -> &[SlotState] {
+        &self.slots
+    }
+
+    pub fn current_slot(&self) -> i32 {
+        self.current
+    }
+
+    /// Returns the tracking id the next contact gets.
+    pub fn next_tracking_id(&self) -> i32 {
+        self.tracking_ids.peek()
+    }
+
+    pub fn is_touching(&self) -> bool {
+        self.slots.iter().any(SlotState::is_active)
+    }
+
+    /// Returns the events taking the slots of `source` from `first_slot` on from `old` to
+    /// `new`, both as the source reported them. The frame still needs its `SYN_REPORT`.
+    ///
+    /// Contacts get tracking ids of the merged device, so sources can't clash on them.
+    pub fn update(
+        &mut self,
+        source: usize,
+        first_slot: usize,
+        old: &[SlotState],
+        new: &[SlotState],
+        time: TimeVal,
+    ) -> Vec<InputEvent> {
+        let event = |event_code, value| InputEvent {
+            time,
+            event_code,
+            value,
+        };
+        let touched_before = self.is_touching();
+        let mut events = Vec::new();
+
+        for (i, (old, new)) in old.iter().zip(new).enumerate() {
+            let slot = first_slot + i;
+            let key = |tracking_id| ContactKey {
+                source,
+                slot: i,
+                tracking_id,
+            };
+            let merged = self.slots[slot];
+            let mut slot_events = Vec::new();
+
+            let lifted = old.is_active() && new.tracking_id != old.tracking_id;
+            let touched = new.is_active() && new.tracking_id != old.tracking_id;
+
+            // axes of a slot without contact aren't shown, they wait for the next one
+            let mut next = if new.is_active() {
+                SlotState {
+                    tracking_id: merged.tracking_id,
+                    ..*new
+                }
+            } else {
+                merged
+            };
+
+            if let (true, Some(id)) = (lifted, old.tracking_id) {
+                self.tracking_ids.release(key(id));
+                slot_events.push(event(EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1));
+                next.tracking_id = None;
+            }
+
+            if let (true, Some(id)) = (touched, new.tracking_id) {
+                let id = self.tracking_ids.get_or_assign(key(id));
+
+                slot_events.push(event(EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), id));
+                next.tracking_id = Some(id);
+            }
+
+            if next.is_active() {
+                for axis in CONTACT_AXES {
+                    let value = next.axis(axis).unwrap();
+
+                    if touched || merged.axis(axis) != Some(value) {
+                        slot_events.push(event(EventCode::EV_ABS(axis), value));
+                    }
+                }
+            }
+
+            self.slots[slot] = next;
+
+            if slot_events.is_empty() {
+                continue;
+            }
+
+            if self.current != slot as i32 {
+                self.current = slot as i32;
+                events.push(event(EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), self.current));
+            }
+
+            events.append(&mut slot_events);
+        }
+
+        let touched_after = self.is_touching();
+
+        if touched_before != touched_after {
+            events.push(event(
+                EventCode::EV_KEY(EV_KEY::BTN_TOUCH),
+                touched_after as i32,
+            ));
+            events.push(event(
+                EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER),
+                touched_after as i32,
+            ));
+        }
+
+        events
+    }
+}
+
+/// Appends the `SYN_REPORT` ending a frame.
+pub fn finish_frame(events: &mut Vec<InputEvent>, time: TimeVal) {
+    events.push(InputEvent {
+        time,
+        event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+        value: 0,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(event_code: EventCode, value: i32) -> InputEvent {
+        InputEvent {
+            time: TimeVal::new(0, 0),
+            event_code,
+            value,
+        }
+    }
+
+    fn abs(axis: EV_ABS, value: i32) -> InputEvent {
+        ev(EventCode::EV_ABS(axis), value)
+    }
+
+    fn key(key: EV_KEY, value: i32) -> InputEvent {
+        ev(EventCode::EV_KEY(key), value)
+    }
+
+    fn syn() -> InputEvent {
+        ev(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0)
+    }
+
+    fn codes(events: &[InputEvent]) -> Vec<(EventCode, i32)> {
+        events.iter().map(|ev| (ev.event_code, ev.value)).collect()
+    }
+
+    /// Feeds `frame` of source `id` owning the slots from `first_slot` on into `merged`.
+    fn feed(
+        merged: &mut MergedSlots,
+        id: usize,
+        source: &mut SourceSlots,
+        first_slot: usize,
+        frame: &[InputEvent],
+    ) -> Vec<(EventCode, i32)> {
+        let old = source.slots().to_vec();
+        source.apply(frame).unwrap();
+
+        codes(&merged.update(id, first_slot, &old, source.slots(), TimeVal::new(0, 0)))
+    }
+
+    fn touch_down(slot: i32, id: i32, x: i32, y: i32) -> Vec<InputEvent> {
+        vec![
+            abs(EV_ABS::ABS_MT_SLOT, slot),
+            abs(EV_ABS::ABS_MT_TRACKING_ID, id),
+            abs(EV_ABS::ABS_MT_POSITION_X, x),
+            abs(EV_ABS::ABS_MT_POSITION_Y, y),
+            key(EV_KEY::BTN_TOUCH, 1),
+            key(EV_KEY::BTN_TOOL_FINGER, 1),
+            syn(),
+        ]
+    }
+
+    #[test]
+    fn tracks_contacts() {
+        let mut source = SourceSlots::new(4);
+
+        source.apply(&touch_down(2, 7, 100, 200)).unwrap();
+
+        assert_eq!(source.current_slot(), 2);
+        assert_eq!(
+            source.slots()[2],
+            SlotState {
+                tracking_id: Some(7),
+                x: 100,
+                y: 200,
+                ..Default::default()
+            }
+        );
+        assert!(source.is_touching());
+
+        source
+            .apply(&[abs(EV_ABS::ABS_MT_TOUCH_MAJOR, 30), syn()])
+            .unwrap();
+        assert_eq!(source.slots()[2].touch_major, 30);
+
+        source
+            .apply(&[
+                abs(EV_ABS::ABS_MT_TRACKING_ID, -1),
+                key(EV_KEY::BTN_TOUCH, 0),
+                syn(),
+            ])
+            .unwrap();
+        assert!(!source.is_touching());
+        // kept for the next contact
+        assert_eq!(source.slots()[2].x, 100);
+    }
+
+    #[test]
+    fn rejects_contradicting_frames() {
+        let mut source = SourceSlots::new(2);
+        source.apply(&touch_down(0, 1, 10, 10)).unwrap();
+
+        assert_eq!(
+            source.apply(&[abs(EV_ABS::ABS_MT_SLOT, 2), syn()]),
+            Err(FrameError::InvalidSlot(2))
+        );
+        assert_eq!(
+            source.apply(&[
+                abs(EV_ABS::ABS_MT_POSITION_X, 50),
+                key(EV_KEY::BTN_TOUCH, 0),
+                syn()
+            ]),
+            Err(FrameError::TouchMismatch)
+        );
+        // neither left a trace
+        assert_eq!(source.slots()[0].x, 10);
+        assert_eq!(source.current_slot(), 0);
+    }
+
+    #[test]
+    fn passes_other_events() {
+        let mut source = SourceSlots::new(1);
+        let others = source
+            .apply(&[
+                abs(EV_ABS::ABS_X, 5),
+                ev(
+                    EventCode::EV_MSC(evdev_rs::enums::EV_MSC::MSC_TIMESTAMP),
+                    1000,
+                ),
+                key(EV_KEY::KEY_WAKEUP, 1),
+                syn(),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            codes(&others),
+            vec![
+                (
+                    EventCode::EV_MSC(evdev_rs::enums::EV_MSC::MSC_TIMESTAMP),
+                    1000
+                ),
+                (EventCode::EV_KEY(EV_KEY::KEY_WAKEUP), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_clean_frames() {
+        let mut merged = MergedSlots::new(8, 65535);
+        let mut source = SourceSlots::new(4);
+
+        assert_eq!(
+            feed(&mut merged, 0, &mut source, 4, &touch_down(1, 40, 100, 200)),
+            vec![
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 5),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), 100),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), 200),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_TOUCH_MAJOR), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_TOUCH_MINOR), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_WIDTH_MAJOR), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_WIDTH_MINOR), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_ORIENTATION), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_PRESSURE), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_TOOL_TYPE), 0),
+                (EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 1),
+                (EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER), 1),
+            ]
+        );
+
+        // only what changed
+        assert_eq!(
+            feed(
+                &mut merged,
+                0,
+                &mut source,
+                4,
+                &[
+                    abs(EV_ABS::ABS_MT_POSITION_X, 100),
+                    abs(EV_ABS::ABS_MT_POSITION_Y, 210),
+                    syn()
+                ]
+            ),
+            vec![(EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), 210)]
+        );
+
+        // a new tracking id of the source is a new contact
+        let events = feed(
+            &mut merged,
+            0,
+            &mut source,
+            4,
+            &[abs(EV_ABS::ABS_MT_TRACKING_ID, 41), syn()],
+        );
+        assert_eq!(
+            &events[..2],
+            &[
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 1),
+            ]
+        );
+        assert_eq!(merged.slots()[5].tracking_id, Some(1));
+    }
+
+    #[test]
+    fn interleaves_sources() {
+        let mut merged = MergedSlots::new(8, 65535);
+        let mut first = SourceSlots::new(4);
+        let mut second = SourceSlots::new(4);
+
+        feed(&mut merged, 0, &mut first, 0, &touch_down(0, 5, 10, 10));
+
+        // the second source touching doesn't touch again, nor does its release lift the first
+        let events = feed(&mut merged, 1, &mut second, 4, &touch_down(0, 5, 20, 20));
+        assert_eq!(events[0], (EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 4));
+        assert_eq!(
+            events[1],
+            (EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 1)
+        );
+        assert!(!events.contains(&(EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 1)));
+
+        let events = feed(
+            &mut merged,
+            1,
+            &mut second,
+            4,
+            &[
+                abs(EV_ABS::ABS_MT_TRACKING_ID, -1),
+                key(EV_KEY::BTN_TOUCH, 0),
+                key(EV_KEY::BTN_TOOL_FINGER, 0),
+                syn(),
+            ],
+        );
+        assert_eq!(
+            events,
+            vec![(EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1)]
+        );
+
+        // moving the first source selects its slot again
+        let events = feed(
+            &mut merged,
+            0,
+            &mut first,
+            0,
+            &[abs(EV_ABS::ABS_MT_POSITION_X, 11), syn()],
+        );
+        assert_eq!(
+            events,
+            vec![
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 0),
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), 11),
+            ]
+        );
+
+        let old = first.slots().to_vec();
+        first.lift_all();
+        let events = merged.update(0, 0, &old, first.slots(), TimeVal::new(0, 0));
+        assert_eq!(
+            codes(&events),
+            vec![
+                (EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1),
+                (EventCode::EV_KEY(EV_KEY::BTN_TOUCH), 0),
+                (EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_ids_of_contacts() {
+        let mut ids = TrackingIds::new(65535);
+        let key = |source, slot, tracking_id| ContactKey {
+            source,
+            slot,
+            tracking_id,
+        };
+
+        assert_eq!(ids.get_or_assign(key(0, 0, 7)), 0);
+        // re-sent by the source
+        assert_eq!(ids.get_or_assign(key(0, 0, 7)), 0);
+        // same source id in another slot or source is another contact
+        assert_eq!(ids.get_or_assign(key(0, 1, 7)), 1);
+        assert_eq!(ids.get_or_assign(key(1, 0, 7)), 2);
+
+        assert_eq!(ids.release(key(0, 0, 7)), Some(0));
+        assert_eq!(ids.release(key(0, 0, 7)), None);
+        // the source reusing its id starts a new contact
+        assert_eq!(ids.get_or_assign(key(0, 0, 7)), 3);
+    }
+
+    #[test]
+    fn wraps_tracking_ids() {
+        let mut ids = TrackingIds::new(3);
+        let key = |tracking_id| ContactKey {
+            source: 0,
+            slot: 0,
+            tracking_id,
+        };
+
+        assert_eq!(ids.get_or_assign(key(10)), 0);
+        assert_eq!(ids.get_or_assign(key(11)), 1);
+        ids.release(key(11));
+        assert_eq!(ids.get_or_assign(key(12)), 2);
+        assert_eq!(ids.get_or_assign(key(13)), 3);
+        assert_eq!(ids.peek(), 0);
+
+        // 0 is still held, 1 was freed
+        assert_eq!(ids.get_or_assign(key(14)), 1);
+        ids.release(key(10));
+        assert_eq!(ids.get_or_assign(key(15)), 0);
+    }
+
+    #[test]
+    fn keeps_merged_ids_in_range() {
+        let mut merged = MergedSlots::new(2, 1);
+        let mut source = SourceSlots::new(1);
+
+        for (tracking_id, expected) in [(100, 0), (101, 1), (102, 0)] {
+            let events = feed(
+                &mut merged,
+                0,
+                &mut source,
+                0,
+                &[abs(EV_ABS::ABS_MT_TRACKING_ID, tracking_id), syn()],
+            );
+
+            assert!(events.contains(&(EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), expected)));
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::bail;
+use evdev_rs::TimeVal;
+use nix::time::{clock_gettime, ClockId};
+use std::str::FromStr;
+
+#[cfg(not(feature = "local"))]
+const CLOCK_PROP: &str = "ro.vendor.gamekeyd.clock";
+
+/// Clock events are stamped with, the touchscreen and the emulator share it so the merged
+/// device sees one timeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Clock {
+    Realtime,
+    /// What Android's EventHub asks input devices for.
+    #[default]
+    Monotonic,
+    Boottime,
+}
+
+impl Clock {
+    /// Reads the clock from `ro.vendor.gamekeyd.clock`, falling back to the monotonic one.
+    #[cfg(not(feature = "local"))]
+    pub fn from_property() -> Self {
+        match rustutils::system_properties::read(CLOCK_PROP) {
+            Ok(Some(text)) if !text.is_empty() => text.parse().unwrap_or_else(|e| {
+                log::error!("Ignoring {}: {:#}", CLOCK_PROP, e);
+                Self::default()
+            }),
+            _ => Self::default(),
+        }
+    }
+
+    #[cfg(feature = "local")]
+    pub fn from_property() -> Self {
+        Self::default()
+    }
+
+    pub fn id(&self) -> ClockId {
+        match self {
+            Clock::Realtime => ClockId::CLOCK_REALTIME,
+            Clock::Monotonic => ClockId::CLOCK_MONOTONIC,
+            Clock::Boottime => ClockId::CLOCK_BOOTTIME,
+        }
+    }
+
+    pub fn now(&self) -> TimeVal {
+        // every clock id above is supported since Linux 2.6.39
+        let now = clock_gettime(self.id()).unwrap();
+
+        TimeVal::new(now.tv_sec(), now.tv_nsec() / 1000)
+    }
+}
+
+impl FromStr for Clock {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "realtime" => Ok(Clock::Realtime),
+            "monotonic" => Ok(Clock::Monotonic),
+            "boottime" => Ok(Clock::Boottime),
+            _ => bail!("Unknown clock {:?}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_clocks() {
+        assert_eq!("boottime".parse::<Clock>().unwrap(), Clock::Boottime);
+        assert_eq!("realtime".parse::<Clock>().unwrap(), Clock::Realtime);
+        assert!("MONOTONIC".parse::<Clock>().is_err());
+    }
+}
@@ -1,2 +1,5 @@
+pub mod clock;
 pub mod counter;
+#[cfg(test)]
+pub mod temp_dir;
 pub mod udev;
\ No newline at end of file
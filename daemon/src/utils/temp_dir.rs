@@ -0,0 +1,31 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// Fresh directory for a test, removed again when dropped, even if the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    // not data_dir(), tests on the device would write next to the real settings
+    pub fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("gamekeyd-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        Self(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}